@@ -0,0 +1,65 @@
+//! throughput of ppcp cp at several --block-size values. on tmpfs, so disks don't hide the difference,
+//! and 4K against 8M on the disk of the temp dir
+#[macro_use]
+extern crate criterion;
+extern crate tempfile;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use criterion::{BenchmarkId, Criterion, Throughput};
+
+const FILE_SIZE: usize = 256 << 20;
+const BLOCK_SIZES: [&str; 4] = ["4K", "64K", "1M", "16M"];
+const DISK_FILE_SIZE: usize = 64 << 20;
+const DISK_BLOCK_SIZES: [&str; 2] = ["4K", "8M"];
+
+/// /dev/shm where there is one
+fn tmpfs() -> tempfile::TempDir {
+    if Path::new("/dev/shm").is_dir() {
+        tempfile::tempdir_in("/dev/shm").expect("temp dir")
+    }
+    else {
+        tempfile::tempdir().expect("temp dir")
+    }
+}
+
+/// group name: file of file_size in tmp copied at each of block_sizes
+fn copy_at_sizes(c: &mut Criterion, name: &str, tmp: &tempfile::TempDir, file_size: usize, block_sizes: &[&str]) {
+    let src = tmp.path().join("src.bin");
+    let dest = tmp.path().join("dest.bin");
+    fs::write(&src, (0..file_size).map(|i| (i % 251) as u8).collect::<Vec<u8>>()).expect("write source");
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Bytes(file_size as u64));
+    group.sample_size(10);
+    for size in block_sizes.iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, size| {
+            b.iter(|| {
+                // read and write loop: copy_file_range and reflink don't use the buffer
+                let status = Command::new(env!("CARGO_BIN_EXE_ppcp"))
+                    .args(&["cp", "-T", "--progress", "none", "--reflink=never", "--block-size", size])
+                    .arg(&src)
+                    .arg(&dest)
+                    .env("XDG_CONFIG_HOME", tmp.path())
+                    .status()
+                    .expect("run ppcp");
+                assert!(status.success());
+            })
+        });
+    }
+    group.finish();
+    assert!(fs::read(&src).unwrap() == fs::read(&dest).unwrap(), "{} differs", dest.display());
+}
+
+fn block_size(c: &mut Criterion) {
+    copy_at_sizes(c, "block_size", &tmpfs(), FILE_SIZE, &BLOCK_SIZES);
+}
+
+/// page cache and disk, smallest block against a large one
+fn block_size_disk(c: &mut Criterion) {
+    copy_at_sizes(c, "block_size_disk", &tempfile::tempdir().expect("temp dir"), DISK_FILE_SIZE, &DISK_BLOCK_SIZES);
+}
+
+criterion_group!(benches, block_size, block_size_disk);
+criterion_main!(benches);
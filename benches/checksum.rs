@@ -0,0 +1,33 @@
+//! --checksum-algorithm speed over 1 GiB. chunks come from memory, like from page cache, so the disk doesn't hide the difference
+#[macro_use]
+extern crate criterion;
+extern crate ppcp;
+
+use criterion::{BenchmarkId, Criterion, Throughput};
+use ppcp::hash::Algorithm;
+
+const FILE_SIZE: usize = 1 << 30;
+/// default --block-size, as the read loop feeds the hasher
+const CHUNK: usize = 128 << 10;
+
+fn checksum(c: &mut Criterion) {
+    let chunk: Vec<u8> = (0..CHUNK).map(|i| (i % 251) as u8).collect();
+    let mut group = c.benchmark_group("checksum");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(10);
+    for algorithm in [Algorithm::Sha256, Algorithm::Xxhash, Algorithm::Blake3].iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(format!("{:?}", algorithm)), algorithm, |b, algorithm| {
+            b.iter(|| {
+                let mut hasher = algorithm.hasher();
+                for _ in 0..FILE_SIZE / CHUNK {
+                    hasher.update(&chunk);
+                }
+                criterion::black_box(hasher.finalize())
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, checksum);
+criterion_main!(benches);
@@ -1,248 +1,420 @@
-use failure::Error;
 use clap::ArgMatches;
-use std::thread;
-use std::path::PathBuf;
+use std::path::Path;
 use path_abs::PathAbs;
 use std::sync::mpsc::*;
 use std::time::*;
 use indicatif::*;
-use std::sync::*;
-use std::ops::{Deref, DerefMut};
+use std::io::{self, BufRead};
+use std::collections::HashSet;
 
-use copy::*;
-use avgspeed::*;
+use ppcp::copy::*;
+use ppcp::events::*;
+use ppcp::oplog::{LogLevel, OpLog};
+use ppcp::error::{PpcpError, Result};
+use ppcp::stats::{OperationStats, Summary};
+use ppcp::util::io_error_message;
+use ppcp::walker::SourceWalker;
 
-pub type Result<T> = std::result::Result<T, Error>;
+use progress::{renderer, ProgressRenderer, REDRAW_INTERVAL};
 
-/// utility to track changes of variable
-#[derive(Default, Clone)]
-pub struct TrackChange<T: PartialEq> {
-    val: T,
-    changed: bool,
+/// --on-error ask: prompts on stdin, remembers "all" answers
+#[derive(Default)]
+pub struct InteractiveErrorHandler {
+    /// errors of these kinds are skipped without asking
+    skip_all: HashSet<io::ErrorKind>,
+    /// answer to all further errors: skip every error or abort
+    error_all: Option<OperationControl>,
 }
 
-impl<T: PartialEq> TrackChange<T> {
-    pub fn new(val: T) -> Self {
-        TrackChange { val, changed: false, }
-    }
-    pub fn changed(&mut self) -> bool {
-        let r = self.changed;
-        self.changed = false;
-        r
-    }
-    pub fn set(&mut self, val: T) {
-        if val == self.val {
-            return
+impl InteractiveErrorHandler {
+    /// bars are updated only from the thread calling this,
+    /// so they are not redrawn over the prompt while we wait for an answer
+    pub fn ask(&mut self, err: String, ctx: &ErrorContext) -> OperationControl {
+        let kind = ctx.err.kind();
+        if let Some(answer) = self.error_all {
+            return answer;
+        }
+        if self.skip_all.contains(&kind) {
+            return OperationControl::Skip;
+        }
+        if !atty::is(atty::Stream::Stdin) {
+            // no one to ask, e.g. run from cron or with input redirected
+            eprintln!("\n{}", err);
+            return OperationControl::Skip;
+        }
+        let stdin = io::stdin();
+        loop {
+            eprint!("\n{}\n(r)etry, (s)kip, (S)kip all {:?} errors, skip (e)very error, (a)bort, (A)bort without asking about other errors? ",
+                    err, kind);
+            let mut answer = String::new();
+            match stdin.lock().read_line(&mut answer) {
+                Ok(0) | Err(_) => return OperationControl::Skip, // stdin closed, no one to ask
+                Ok(_) => {},
+            }
+            match answer.trim() {
+                "r" => return OperationControl::Retry,
+                "s" => return OperationControl::Skip,
+                "S" => {
+                    self.skip_all.insert(kind);
+                    return OperationControl::Skip;
+                }
+                "e" => {
+                    self.error_all = Some(OperationControl::Skip);
+                    return OperationControl::Skip;
+                }
+                // other workers finish their files, their errors are still asked about
+                "a" => return OperationControl::Abort,
+                "A" => {
+                    self.error_all = Some(OperationControl::Abort);
+                    return OperationControl::Abort;
+                }
+                _ => {},
+            }
         }
-        self.changed = true;
-        self.val = val;
-    }
-}
-impl<T: PartialEq> Deref for TrackChange<T> {
-    type Target = T;
-    fn deref(&self) -> &T {
-        &self.val
-    }
-}
-impl<T: PartialEq> DerefMut for TrackChange<T> {
-    fn deref_mut(&mut self) -> &mut T {
-        self.changed = true; // XXX not checking prev value
-        &mut self.val
     }
 }
 
-pub struct OperationStats {
-    files_done: u32,
-    bytes_done: u64,
-    files_total: TrackChange<u64>,
-    bytes_total: TrackChange<u64>,
-    current_total: TrackChange<u64>,
-    current_done: u64,
-    current_path: TrackChange<PathBuf>,
-    current_start: Instant,
+pub struct App {
+    /// bars, spinner, json lines or nothing, see --progress
+    progress: Box<dyn ProgressRenderer>,
+    /// avg_speed of stats was sampled
+    last_sample: Instant,
+    /// --on-error
+    on_error: OnError,
+    errors: InteractiveErrorHandler,
+    /// answer to all conflicts, if user chose "all"
+    conflict_all: Option<OperationControl>,
+    /// --log-file
+    log: Option<OpLog>,
+    /// --progress json: stdout is for json lines only
+    json: bool,
+    /// --quiet: summary without details, nothing drawn before it
+    quiet: bool,
 }
 
-impl Default for OperationStats {
-    fn default() -> Self {
-        OperationStats {
-            files_done: 0,
-            bytes_done: 0,
-            files_total: TrackChange::new(0),
-            bytes_total: TrackChange::new(0),
-            current_total: TrackChange::new(0),
-            current_done: 0,
-            current_path: TrackChange::new(PathBuf::new()),
-            current_start: Instant::now(),
+impl App {
+    pub fn new(matches: &ArgMatches) -> Self {
+        let (progress, on_error, json, quiet) = match matches.subcommand() {
+            // values are checked by clap
+            (command, Some(matches)) => {
+                let json = matches.is_present("json-progress") || matches.value_of("progress") == Some("json");
+                // wins over --progress, it may come from config
+                let quiet = matches.is_present("quiet") && !json;
+                let mode = if quiet { "none" } else { matches.value_of("progress").unwrap_or("auto") };
+                (renderer(mode, json, command == "sync"),
+                 OnError::parse(matches.value_of("on-error").unwrap_or("ask")).unwrap_or(OnError::Ask),
+                 json,
+                 quiet)
+            }
+            _ => (renderer("auto", false, false), OnError::Ask, false, false),
+        };
+        App {
+            progress,
+            last_sample: Instant::now(),
+            on_error,
+            errors: InteractiveErrorHandler::default(),
+            conflict_all: None,
+            log: None,
+            json,
+            quiet,
         }
     }
-}
 
-struct SourceWalker {
-}
+    fn log(&mut self, level: LogLevel, event: &str, line: serde_json::Value) {
+        if let Some(ref mut log) = self.log {
+            log.write(level, event, line);
+        }
+    }
 
-impl SourceWalker {
-    fn run(tx: Sender<(PathBuf, PathBuf, u64, std::fs::Permissions, bool)>, sources: Vec<PathBuf>) {
-        thread::spawn(move || {
-            for src in sources {
-                // let src = PathAbs::new(&src).unwrap().as_path().to_owned();
-                let src = src.canonicalize().unwrap();
-                for entry in walkdir::WalkDir::new(src.clone()) {
-                    match entry {
-                        Ok(entry) => {
-                            if entry.file_type().is_file() || entry.path_is_symlink() {
-                                let m = entry.metadata().unwrap();
-                                let size = m.len();
-                                let perm = m.permissions();
-                                let is_link = m.file_type().is_symlink();
-                                tx.send((src.clone(), entry.into_path(), size, perm, is_link)).expect("send");
-                            }
-                        }
-                        Err(_) => {
-                            // TODO
-                        }
-                    }
-                }
+    /// --log-file line for file operations. errors are logged with the answer to them, see log_error
+    fn log_event(&mut self, event: &WorkerEvent, stats: &OperationStats) {
+        match *event {
+            WorkerEvent::Status(OperationStatus::Warning(ref msg)) => {
+                self.log(LogLevel::Warn, "warning", json!({ "message": msg }));
+            }
+            WorkerEvent::VerifyError(ref p) => {
+                self.log(LogLevel::Error, "verify_failed", json!({ "dst": p.display().to_string() }));
             }
-        });
+            WorkerEvent::Started { ref src, ref dst } => {
+                self.log(LogLevel::Info, "started", json!({
+                    "src": src.display().to_string(),
+                    "dst": dst.display().to_string(),
+                }));
+            }
+            WorkerEvent::Finished { ref src, ref dst, bytes, elapsed } => {
+                self.log(LogLevel::Info, "copied", json!({
+                    "src": src.display().to_string(),
+                    "dst": dst.display().to_string(),
+                    "bytes": bytes,
+                    "duration_ms": elapsed.as_millis() as u64,
+                }));
+            }
+            WorkerEvent::Deleted(ref p) => {
+                self.log(LogLevel::Info, "deleted", json!({ "dst": p.display().to_string() }));
+            }
+            // same mount under several sources
+            WorkerEvent::Stat(StatsChange::MountSkipped(ref path)) if !stats.mounts_skipped.contains(path) => {
+                self.log(LogLevel::Info, "mount_skipped", json!({ "src": path.display().to_string() }));
+            }
+            WorkerEvent::Stat(StatsChange::SourceMissing(ref path)) => {
+                self.log(LogLevel::Warn, "missing", json!({ "src": path.display().to_string() }));
+            }
+            WorkerEvent::Stat(StatsChange::ConflictRenamed(ref existing, ref free)) => {
+                self.log(LogLevel::Info, "conflict_renamed", json!({
+                    "dst": existing.display().to_string(),
+                    "renamed": free.display().to_string(),
+                }));
+            }
+            WorkerEvent::Stat(StatsChange::FileSkipped(size, reason, ref p)) => {
+                self.log(LogLevel::Info, "skipped", json!({ "src": p.display().to_string(), "bytes": size, "reason": reason.to_string() }));
+            }
+            WorkerEvent::Stat(StatsChange::FileFailed(done, _, ref p, ref reason)) => {
+                self.log(LogLevel::Error, "failed", json!({ "src": p.display().to_string(), "bytes": done, "error": reason }));
+            }
+            WorkerEvent::Stat(StatsChange::DirFailed(ref p, ref reason)) => {
+                self.log(LogLevel::Error, "dir_failed", json!({ "src": p.display().to_string(), "error": reason }));
+            }
+            _ => {}
+        }
     }
-}
 
-pub struct App {
-    pb_curr: ProgressBar,
-    pb_files: ProgressBar,
-    pb_bytes: ProgressBar,
-    pb_name: ProgressBar,
-    last_update: Instant,
-    pb_done: Arc<Mutex<()>>,
-    avg_speed: AvgSpeed,
-}
+    /// error with what was done about it
+    fn log_error(&mut self, event: &str, ctx: &ErrorContext, answer: OperationControl) {
+        self.log(LogLevel::Error, event, json!({
+            "src": ctx.src.display().to_string(),
+            "dst": ctx.dest.as_ref().map(|dest| dest.display().to_string()),
+            "error": format!("{:?} ({})", ctx.err.kind(), ctx.err),
+            "decision": format!("{:?}", answer),
+        }));
+    }
 
-impl App {
-    pub fn new() -> Self {
-        let pb_name = ProgressBar::with_draw_target(10, ProgressDrawTarget::stdout_nohz());
-        // \u{00A0} (nbsp) to make indicatif draw lines as wide as possible
-        // otherwise it leaves leftovers from prev lines at the end of lines
-        pb_name.set_style(ProgressStyle::default_spinner()
-            .template("{spinner} {wide_msg} \u{00A0}")
-        );
-        let pb_curr = ProgressBar::new(10);
-        pb_curr.set_style(ProgressStyle::default_bar()
-            .template("current {bar:40.} {bytes:>8}/{total_bytes:<8} {elapsed:>5} ETA {eta} {wide_msg} \u{00A0}")
-        );
-        let pb_files = ProgressBar::with_draw_target(10, ProgressDrawTarget::stdout_nohz());
-        pb_files.set_style(ProgressStyle::default_bar()
-            .template("files   {bar:40} {pos:>8}/{len:<8} {wide_msg} \u{00A0}")
-        );
-        let pb_bytes = ProgressBar::with_draw_target(10, ProgressDrawTarget::stdout_nohz());
-        pb_bytes.set_style(ProgressStyle::default_bar()
-            .template("bytes   {bar:40} {bytes:>8}/{total_bytes:<8} {elapsed:>5} ETA {eta} {wide_msg} \u{00A0}")
-            // .progress_chars("=> ")
-        );
-        let multi_pb = MultiProgress::new();
-        let pb_name = multi_pb.add(pb_name);
-        let pb_curr = multi_pb.add(pb_curr);
-        let pb_files = multi_pb.add(pb_files);
-        let pb_bytes = multi_pb.add(pb_bytes);
-        multi_pb.set_move_cursor(true);
-        let pb_done = Arc::new(Mutex::new(()));
-        let pb_done2 = pb_done.clone();
-        thread::spawn(move || {
-            let _locked = pb_done2.lock().unwrap();
-            multi_pb.join().expect("join");
-        });
-        
-        App {
-            pb_curr,
-            pb_files,
-            pb_bytes,
-            pb_name,
-            last_update: Instant::now(),
-            pb_done,
-            avg_speed: AvgSpeed::new(),
-        }
-    }
-
-    // fn error_ask(&self, err: String) -> OperationControl {
-    //     OperationControl::Skip // TODO
-    // }
-
-    fn update_progress(&mut self, stats: &mut OperationStats) {
-        // return;
-        if Instant::now().duration_since(self.last_update) < Duration::from_millis(97) {
-            return
-        }
-        self.last_update = Instant::now();
-        self.pb_name.tick(); // spin the spinner
-        if stats.current_path.changed() {
-            self.pb_name.set_message(&format!("{}", stats.current_path.display()));
-            self.pb_curr.set_length(*stats.current_total as u64);
-            stats.current_start = Instant::now(); // This is inaccurate. Init current_start in copy worker and send instant with path?
-            self.pb_curr.reset_elapsed();
-            self.pb_curr.reset_eta();
-        }
-        self.pb_curr.set_draw_delta(0);
-        self.pb_curr.set_position(stats.current_done as u64);
-        self.avg_speed.add(stats.bytes_done);
-        self.pb_curr.set_message(&format!("{}/s", HumanBytes(self.avg_speed.get() as u64)));
-
-        if stats.files_total.changed() {
-            self.pb_files.set_length(*stats.files_total as u64);
-        }
-        self.pb_files.set_position(u64::from(stats.files_done));
-        
-        if stats.bytes_total.changed() {
-            self.pb_bytes.set_length(*stats.bytes_total as u64);
+    /// ask user what to do with the error, unless --on-error says it already
+    fn error_ask(&mut self, err: String, ctx: &ErrorContext) -> OperationControl {
+        match self.on_error {
+            OnError::Ask => self.errors.ask(err, ctx),
+            // retry:N failed after retries, --retries only retried transient errors
+            OnError::Skip | OnError::Retry(_) => OperationControl::Skip,
+            OnError::Abort => {
+                eprintln!("\n{}", err);
+                OperationControl::Abort
+            }
+        }
+    }
+
+    /// ask if existing dest should be overwritten
+    fn conflict_ask(&mut self, src: &Path, dest: &Path) -> OperationControl {
+        if let Some(answer) = self.conflict_all {
+            return answer;
+        }
+        if !atty::is(atty::Stream::Stdin) {
+            // don't overwrite if can't ask
+            return OperationControl::SkipFile;
+        }
+        let stdin = io::stdin();
+        loop {
+            eprint!("\n{} already exists, copying {}\n(o)verwrite, (k)eep both, (s)kip, (O)verwrite all, (K)eep both all, (S)kip all? ",
+                    dest.display(), src.display());
+            let mut answer = String::new();
+            match stdin.lock().read_line(&mut answer) {
+                Ok(0) | Err(_) => return OperationControl::SkipFile, // don't overwrite if can't ask
+                Ok(_) => {},
+            }
+            let (answer, all) = match answer.trim() {
+                "o" => (OperationControl::Overwrite, false),
+                "k" => (OperationControl::KeepBoth, false),
+                "s" => (OperationControl::SkipFile, false),
+                "O" => (OperationControl::Overwrite, true),
+                "K" => (OperationControl::KeepBoth, true),
+                "S" => (OperationControl::SkipFile, true),
+                _ => continue,
+            };
+            if all {
+                self.conflict_all = Some(answer);
+            }
+            return answer;
         }
-        self.pb_bytes.set_position(stats.bytes_done as u64);
     }
 
-    pub fn run(&mut self, matches: &ArgMatches) -> Result<()> {
+    pub fn run(&mut self, matches: &ArgMatches) -> Result<Summary> {
         // for sending errors, progress info and other events from worker to ui:
         let (worker_tx, worker_rx) = channel::<WorkerEvent>();
-        // TODO for sending user input (retry/skip/abort) to worker:
-        let (_user_tx, user_rx) = channel::<OperationControl>();
+        // for sending user input (retry/skip/abort) to worker:
+        let (user_tx, user_rx) = channel::<OperationControl>();
         // fs walker sends files to operation
         let (src_tx, src_rx) = channel();
 
-        let operation = OperationCopy::new(&matches, user_rx, worker_tx, src_rx)?;
+        let (command, matches) = match matches.subcommand() {
+            (command, Some(matches)) => (command, matches),
+            _ => Err(PpcpError::ArgumentsMissing)?,
+        };
+        let walker = SourceWalker::new(matches)?;
+        if let Some(path) = matches.value_of("log-file") {
+            // value is checked by clap
+            let level = LogLevel::parse(matches.value_of("log-level").unwrap_or("info"))?;
+            let mut log = OpLog::open(Path::new(path), level)?;
+            // runs appended to the same file are told apart by it
+            let args: Vec<String> = std::env::args_os().map(|arg| arg.to_string_lossy().into_owned()).collect();
+            let cwd = std::env::current_dir().map(|dir| dir.display().to_string()).unwrap_or_default();
+            log.record("command", json!({ "args": args, "cwd": cwd }));
+            self.log = Some(log);
+        }
+        let walker_tx = worker_tx.clone();
+        let operation: Box<dyn Operation> = match command {
+            "cp" => Box::new(OperationCopy::new(matches, user_rx, worker_tx, src_rx)?),
+            "mv" => Box::new(OperationMove::new(matches, user_rx, worker_tx, src_rx)?),
+            "sync" => Box::new(OperationSync::new(matches, user_rx, worker_tx, src_rx)?),
+            _ => Err(PpcpError::ArgumentsMissing)?,
+        };
         
+        // empty if mv renamed everything
         let search_path = operation.search_path();
-        assert!(!search_path.is_empty());
-        SourceWalker::run(src_tx, search_path);
+        walker.run(src_tx, walker_tx, search_path);
 
         let mut stats: OperationStats = Default::default();
 
-        let start = Instant::now();
-
         while let Ok(event) = worker_rx.recv() {
+            // before update: mount points are logged once
+            self.log_event(&event, &stats);
+            stats.update(&event);
             match event {
-                WorkerEvent::Stat(StatsChange::FileDone) => { stats.files_done += 1 }
-                WorkerEvent::Stat(StatsChange::BytesTotal(n)) => {
-                    *stats.bytes_total += n;
-                    *stats.files_total += 1;
+                WorkerEvent::Status(OperationStatus::Error(ref ctx)) => {
+                    let answer = self.error_ask(io_error_message(&ctx.src, &ctx.err), ctx);
+                    self.log_error("error", ctx, answer);
+                    user_tx.send(answer).expect("send");
                 },
-                WorkerEvent::Stat(StatsChange::Current(p, chunk, done, todo)) => {
-                    stats.current_path.set(p);
-                    stats.current_total.set(todo);
-                    stats.current_done = done;
-                    stats.bytes_done += u64::from(chunk);
-                }
-                // WorkerEvent::Status(OperationStatus::Error(err)) => {
-                //     let answer = self.error_ask(err);
-                //     user_tx.send(answer).expect("send");
-                // },
-                // _ => {},
-            }
-            self.update_progress(&mut stats);
-        }
-        self.pb_curr.finish();
-        self.pb_files.finish();
-        self.pb_bytes.finish();
-        self.pb_name.finish();
-        let ela = Instant::now().duration_since(start);
-        let _locked = self.pb_done.lock().unwrap();
-        println!("copied {} files ({}) in {} {}/s", *stats.files_total, HumanBytes(*stats.bytes_total as u64), HumanDuration(ela),
-                 HumanBytes(get_speed(*stats.bytes_total, &ela) as u64));
-        Ok(())
+                WorkerEvent::Status(OperationStatus::MetadataError(ref ctx)) => {
+                    let dest = ctx.dest.as_ref().unwrap_or(&ctx.src);
+                    let answer = self.error_ask(format!("can't preserve metadata of {}", io_error_message(dest, &ctx.err)), ctx);
+                    self.log_error("metadata_error", ctx, answer);
+                    user_tx.send(answer).expect("send");
+                },
+                WorkerEvent::Status(OperationStatus::Conflict(ref src, ref dest)) => {
+                    let answer = self.conflict_ask(src, dest);
+                    user_tx.send(answer).expect("send");
+                },
+                // counted by stats.update. no wildcard: a new event that waits for an answer must be answered here
+                WorkerEvent::Stat(_) | WorkerEvent::Status(OperationStatus::Warning(_)) | WorkerEvent::VerifyError(_)
+                | WorkerEvent::Deleted(_) | WorkerEvent::Scanning(..) | WorkerEvent::WalkDone
+                | WorkerEvent::Started { .. } | WorkerEvent::Finished { .. } => {},
+            }
+            if Instant::now().duration_since(self.last_sample) >= REDRAW_INTERVAL {
+                self.last_sample = Instant::now();
+                stats.sample_speed();
+            }
+            self.progress.on_event(&event, &mut stats);
+        }
+        let summary = stats.summary(operation.as_ref());
+        if let Some(ref mut log) = self.log {
+            log.record("summary", serde_json::to_value(&summary).expect("json"));
+        }
+        self.progress.finish(&summary);
+        if !self.json && !self.quiet {
+            print_details(&stats, &summary, matches.is_present("verbose"));
+        }
+        if !stats.sources_missing.is_empty() {
+            eprintln!("{} listed paths not found:", stats.sources_missing.len());
+            for path in stats.sources_missing.iter() {
+                eprintln!("  {}", path.display());
+            }
+        }
+        for msg in stats.warnings.iter() {
+            eprintln!("warning: {}", msg);
+        }
+        if stats.owner_not_preserved > 0 {
+            eprintln!("warning: ownership could not be preserved for {} files", stats.owner_not_preserved);
+        }
+        if stats.xattrs_not_preserved > 0 {
+            eprintln!("warning: extended attributes could not be preserved for {} files", stats.xattrs_not_preserved);
+        }
+        if stats.acl_not_preserved > 0 {
+            eprintln!("warning: acl could not be preserved for {} files and dirs, destination doesn't support acls", stats.acl_not_preserved);
+        }
+        if !stats.failed.is_empty() {
+            eprintln!("failed {} files:", stats.failed.len());
+            for (p, reason) in stats.failed.iter() {
+                eprintln!("  {}: {}", p.display(), reason);
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// lines of stdout after the summary
+fn print_details(stats: &OperationStats, summary: &Summary, verbose: bool) {
+    let bytes_copied = *stats.bytes_total - stats.bytes_renamed;
+    if stats.files_renamed > 0 {
+        let mut line = format!("renamed {} files ({}) without copying", stats.files_renamed, HumanBytes(stats.bytes_renamed));
+        if bytes_copied > 0 {
+            // how long would it take to copy renamed files at the speed of the rest
+            let speed = summary.avg_speed.max(1);
+            line += &format!(", saved ~{}", HumanDuration(Duration::from_secs(stats.bytes_renamed / speed)));
+        }
+        println!("{}", line);
+    }
+    if stats.files_cloned > 0 {
+        println!("{} files cloned with reflink, {} copied", stats.files_cloned, summary.files_copied - stats.files_cloned);
+    }
+    if stats.files_linked > 0 {
+        println!("{} files hard-linked", stats.files_linked);
+    }
+    if stats.files_updated > 0 {
+        println!("{} of files copied replaced older versions in destination", stats.files_updated);
+    }
+    if stats.symlinks_copied > 0 {
+        println!("{} of files copied are symlinks, copied as links", stats.symlinks_copied);
+    }
+    if stats.specials_copied > 0 {
+        println!("{} of files copied are named pipes or device nodes, recreated", stats.specials_copied);
+    }
+    if stats.dirs_created > 0 {
+        println!("{} {} directories", if summary.dry_run { "would create" } else { "created" }, stats.dirs_created);
+    }
+    if stats.files_up_to_date > 0 {
+        println!("skipped {} files, destination is up to date", stats.files_up_to_date);
+    }
+    if stats.files_skipped > stats.files_up_to_date {
+        println!("skipped {} files, destination exists", stats.files_skipped - stats.files_up_to_date);
+    }
+    if stats.files_deleted > 0 {
+        println!("deleted {} files not in source", stats.files_deleted);
+    }
+    if stats.files_excluded > 0 {
+        println!("excluded {} files and dirs", stats.files_excluded);
+    }
+    if stats.files_filtered > 0 {
+        println!("skipped {} files out of size or time range", stats.files_filtered);
+    }
+    let specials_skipped = [(stats.fifos_skipped, "named pipes"), (stats.sockets_skipped, "sockets"), (stats.devices_skipped, "device nodes")];
+    if specials_skipped.iter().any(|&(n, _)| n > 0) {
+        let counts: Vec<String> = specials_skipped.iter().filter(|&&(n, _)| n > 0).map(|&(n, kind)| format!("{} {}", n, kind)).collect();
+        println!("skipped special files: {}. --special-files recreates pipes, and device nodes as root", counts.join(", "));
+    }
+    if stats.hidden_skipped > 0 && verbose {
+        println!("skipped {} hidden files and dirs", stats.hidden_skipped);
+    }
+    if !stats.mounts_skipped.is_empty() {
+        println!("skipped {} mount points", stats.mounts_skipped.len());
+        if verbose {
+            for path in stats.mounts_skipped.iter() {
+                println!("  {}", path.display());
+            }
+        }
+    }
+    if stats.files_verified > 0 || stats.verify_errors > 0 {
+        println!("verified {} files ({}), {} checksum mismatches", stats.files_verified, HumanBytes(stats.bytes_verified), stats.verify_errors);
+    }
+    if !stats.conflicts_renamed.is_empty() {
+        println!("{} files {} to avoid conflicts", stats.conflicts_renamed.len(), if summary.dry_run { "would be renamed" } else { "renamed" });
+        if verbose {
+            for (existing, free) in stats.conflicts_renamed.iter() {
+                println!("  {} -> {}", existing.display(), free.display());
+            }
+        }
+    }
+    if !stats.conflicts.is_empty() {
+        println!("{} files already exist:", stats.conflicts.len());
+        for p in stats.conflicts.iter() {
+            println!("  {}", p.display());
+        }
     }
 }
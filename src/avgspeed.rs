@@ -14,6 +14,7 @@ impl<T> RollingAverage<T>
 // moments like this I miss dating duck typing
 where T: AddAssign + SubAssign + Div + std::convert::From<u64> + std::convert::From<<T as std::ops::Div>::Output> + Copy
 {
+    /// average of last size values
     pub fn new(size: usize) -> Self {
         RollingAverage {
             hist: VecDeque::with_capacity(size),
@@ -21,6 +22,7 @@ where T: AddAssign + SubAssign + Div + std::convert::From<u64> + std::convert::F
             size,
         }
     }
+    /// oldest value is dropped when there are more than size
     pub fn add(&mut self, val: T) {
         self.hist.push_back(val);
         self.sum += val;
@@ -28,11 +30,16 @@ where T: AddAssign + SubAssign + Div + std::convert::From<u64> + std::convert::F
             self.sum -= self.hist.pop_front().unwrap();
         }
     }
+    /// average of values added so far, 0 if there are none
     pub fn get(&self) -> T {
+        if self.hist.is_empty() {
+            return (0 as u64).into();
+        }
         (self.sum / (self.hist.len() as u64).into()).into()
     }
 }
 
+/// bytes per second, average of last 100 updates
 pub struct AvgSpeed {
     avg: RollingAverage<u64>,
     prev_bytes: u64,
@@ -40,6 +47,7 @@ pub struct AvgSpeed {
 }
 
 impl AvgSpeed {
+    /// no bytes yet, time starts now
     pub fn new() -> Self {
         AvgSpeed {
             avg: RollingAverage::new(100),
@@ -47,32 +55,226 @@ impl AvgSpeed {
             last_chunk: Instant::now(),
         }
     }
+    /// total_bytes done so far, not since last call
     pub fn add(&mut self, total_bytes: u64) {
-        let db = total_bytes - self.prev_bytes;
+        // bytes done go back on retry or failed file: no bytes this time, count from there
+        let db = total_bytes.saturating_sub(self.prev_bytes);
         self.avg.add(get_speed(db, &Instant::now().duration_since(self.last_chunk)));
         self.last_chunk = Instant::now();
         self.prev_bytes = total_bytes;
     }
+    /// bytes per second
     pub fn get(&self) -> u64 {
         self.avg.get()
     }
 }
-    
-pub fn get_speed(x: u64, ela: &Duration) -> u64{
-    if *ela >= Duration::from_nanos(1) && x < std::u64::MAX/1_000_000_000 {
-        x * 1_000_000_000 / ela.as_nanos() as u64
+
+/// exponential moving average of speed. higher alpha follows changes faster,
+/// lower forgets slow start of a file slower. ETA of current file bar
+pub struct EmaSpeed {
+    alpha: f64,
+    speed: Option<f64>,
+}
+
+impl EmaSpeed {
+    /// alpha from 0 to 1, weight of newest sample
+    pub fn new(alpha: f64) -> Self {
+        EmaSpeed {
+            alpha,
+            speed: None,
+        }
+    }
+    /// bytes transferred since previous update
+    pub fn update(&mut self, bytes: u64, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return;
+        }
+        let speed = bytes as f64 / secs;
+        self.speed = Some(match self.speed {
+            Some(avg) => self.alpha * speed + (1.0 - self.alpha) * avg,
+            None => speed,
+        });
+    }
+    /// bytes per second, 0 before first update
+    pub fn get(&self) -> u64 {
+        self.speed.unwrap_or(0.0) as u64
+    }
+    /// forget speed, like for next file
+    pub fn reset(&mut self) {
+        self.speed = None;
+    }
+    /// time to transfer remaining bytes at current speed
+    pub fn eta(&self, remaining: u64) -> Option<Duration> {
+        match self.speed {
+            Some(speed) if speed >= 1.0 => Some(Duration::from_secs_f64(remaining as f64 / speed)),
+            _ => None,
+        }
     }
-    else if *ela >= Duration::from_micros(1) && x < std::u64::MAX/1_000_000 {
-        x * 1_000_000 / ela.as_micros() as u64
+}
+
+/// speed over last few seconds: follows changes in the middle of a file,
+/// unlike bytes since file start
+pub struct WindowSpeed {
+    window: Duration,
+    /// time, bytes done so far. oldest first
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl WindowSpeed {
+    /// speed over last window of time
+    pub fn new(window: Duration) -> Self {
+        WindowSpeed {
+            window,
+            samples: VecDeque::new(),
+        }
     }
-    else if *ela >= Duration::from_millis(1) && x < std::u64::MAX/1_000 {
-        x * 1_000 / ela.as_millis() as u64
+    /// bytes done of the file so far
+    pub fn add(&mut self, done: u64) {
+        let now = Instant::now();
+        if self.samples.back().map_or(false, |s| s.1 > done) {
+            // file is copied again after error
+            self.samples.clear();
+        }
+        self.samples.push_back((now, done));
+        // keep one sample older than window, so the window is full
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= self.window {
+            self.samples.pop_front();
+        }
     }
-    else if *ela >= Duration::from_secs(1) {
-        x / ela.as_secs() as u64
+    /// forget samples, like for next file
+    pub fn reset(&mut self) {
+        self.samples.clear();
     }
-    else {
+    /// bytes per second over the window, 0 until there are two samples
+    pub fn get(&self) -> u64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(first), Some(last)) if last.0 > first.0 => get_speed(last.1 - first.1, &last.0.duration_since(first.0)),
+            _ => 0,
+        }
+    }
+    /// time to transfer remaining bytes at current speed
+    pub fn eta(&self, remaining: u64) -> Option<Duration> {
+        match self.get() {
+            0 => None,
+            speed => Some(Duration::from_secs(remaining / speed)),
+        }
+    }
+}
+    
+/// bytes per second. u128 math doesn't overflow for any byte count and elapsed time
+pub fn get_speed(x: u64, ela: &Duration) -> u64 {
+    let nanos = ela.as_nanos();
+    if nanos == 0 {
         // what the hell are you?
-        std::u64::MAX
+        return std::u64::MAX;
+    }
+    (x as u128 * 1_000_000_000 / nanos).min(std::u64::MAX as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// within 0.1% of expected
+    fn assert_close(actual: u64, expected: u64) {
+        let diff = if actual > expected { actual - expected } else { expected - actual };
+        assert!(diff <= expected / 1000, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn avg_speed_bytes_going_back_is_no_bytes() {
+        let mut speed = AvgSpeed::new();
+        speed.add(1000);
+        // file failed, its bytes are taken away
+        speed.add(400);
+        assert_eq!(speed.avg.hist.back(), Some(&0));
+        assert_eq!(speed.prev_bytes, 400);
+        speed.add(1400);
+        assert_eq!(speed.prev_bytes, 1400);
+    }
+
+    #[test]
+    fn ema_first_update_is_speed() {
+        for &alpha in [0.0, 0.1, 0.5, 1.0].iter() {
+            let mut ema = EmaSpeed::new(alpha);
+            assert_eq!(ema.get(), 0);
+            assert_eq!(ema.eta(100), None);
+            ema.update(1000, Duration::from_millis(500));
+            assert_eq!(ema.get(), 2000);
+        }
+    }
+
+    #[test]
+    fn ema_alpha_weighs_new_speed() {
+        // 1000 B/s, then 3000 B/s
+        let table = [(0.0, 1000), (0.1, 1200), (0.25, 1500), (0.5, 2000), (0.9, 2800), (1.0, 3000)];
+        for &(alpha, expected) in table.iter() {
+            let mut ema = EmaSpeed::new(alpha);
+            ema.update(1000, Duration::from_secs(1));
+            ema.update(3000, Duration::from_secs(1));
+            assert_close(ema.get(), expected);
+        }
+    }
+
+    #[test]
+    fn ema_converges_to_steady_speed() {
+        for &alpha in [0.05, 0.3, 0.8].iter() {
+            let mut ema = EmaSpeed::new(alpha);
+            // slow start, like a cold cache
+            ema.update(10, Duration::from_secs(1));
+            for _ in 0..1000 {
+                ema.update(1 << 20, Duration::from_millis(100));
+            }
+            assert_close(ema.get(), 10 << 20);
+            assert_eq!(ema.eta(20 << 20), Some(Duration::from_secs(2)));
+        }
+    }
+
+    #[test]
+    fn ema_ignores_zero_elapsed() {
+        let mut ema = EmaSpeed::new(0.5);
+        ema.update(1000, Duration::from_secs(1));
+        ema.update(1 << 30, Duration::from_secs(0));
+        assert_eq!(ema.get(), 1000);
+    }
+
+    #[test]
+    fn ema_reset_starts_over() {
+        let mut ema = EmaSpeed::new(0.1);
+        ema.update(1000, Duration::from_secs(1));
+        ema.reset();
+        assert_eq!(ema.eta(100), None);
+        // first update after reset is the speed, not mixed with the old one
+        ema.update(5000, Duration::from_secs(1));
+        assert_eq!(ema.get(), 5000);
+    }
+
+    #[test]
+    fn get_speed_ranges() {
+        let table = [
+            // bytes, elapsed, bytes per second
+            (1_000_000, Duration::from_secs(2), 500_000),
+            (3 << 30, Duration::from_secs(3), 1 << 30),
+            (1_000_000, Duration::from_millis(250), 4_000_000),
+            (123_456, Duration::from_millis(1), 123_456_000),
+            (4096, Duration::from_micros(100), 40_960_000),
+            (1, Duration::from_micros(1), 1_000_000),
+            (1, Duration::from_nanos(1), 1_000_000_000),
+            (4096, Duration::from_nanos(333), 12_300_300_300),
+            (0, Duration::from_secs(1), 0),
+        ];
+        for &(bytes, ela, speed) in table.iter() {
+            assert_close(get_speed(bytes, &ela), speed);
+        }
+    }
+
+    #[test]
+    fn get_speed_doesnt_overflow() {
+        assert_eq!(get_speed(std::u64::MAX, &Duration::from_secs(1)), std::u64::MAX);
+        assert_eq!(get_speed(std::u64::MAX, &Duration::from_nanos(1)), std::u64::MAX);
+        assert_close(get_speed(std::u64::MAX, &Duration::from_secs(1000)), std::u64::MAX / 1000);
+        assert_eq!(get_speed(1, &Duration::from_secs(2)), 0);
+        assert_eq!(get_speed(1, &Duration::from_nanos(0)), std::u64::MAX);
     }
 }
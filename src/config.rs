@@ -0,0 +1,196 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use clap::ArgMatches;
+
+use ppcp::copy::OnError;
+use ppcp::error::{PpcpError, Result};
+use ppcp::util::parse_size;
+
+/// written by config --edit when there is no file yet
+const TEMPLATE: &str = "\
+# defaults of ppcp cp, mv and sync. options given in command line win
+# jobs = 4
+# block-size = \"1M\"
+# on-error = \"retry:3\"
+# progress = \"auto\"
+# checked after --exclude and --include of command line
+# exclude = [\"*.swp\", \"node_modules/**\"]
+";
+
+const DEFAULT_JOBS: usize = 1;
+const DEFAULT_BLOCK_SIZE: &str = "128K";
+const DEFAULT_ON_ERROR: &str = "ask";
+const DEFAULT_PROGRESS: &str = "auto";
+
+/// ~/.config/ppcp/config.toml, or in $XDG_CONFIG_HOME. values have the format of options
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub jobs: Option<usize>,
+    pub block_size: Option<String>,
+    pub on_error: Option<String>,
+    pub progress: Option<String>,
+    pub exclude: Vec<String>,
+}
+
+/// config values as defaults of clap arguments
+pub struct Defaults {
+    pub jobs: String,
+    pub block_size: String,
+    pub on_error: String,
+    pub progress: String,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("ppcp").join("config.toml"))
+    }
+
+    /// empty config if there is no file
+    pub fn load() -> Result<Self> {
+        let path = match Config::path() {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(err) => Err(err)?,
+        };
+        Config::parse(&text, &path)
+    }
+
+    /// text of file at path, path is for errors
+    fn parse(text: &str, path: &Path) -> Result<Self> {
+        let invalid = |message: String| PpcpError::Config { path: path.display().to_string(), message };
+        let config: Config = toml::from_str(text).map_err(|err| invalid(err.to_string()))?;
+        // checked here, clap doesn't validate default values
+        if config.jobs == Some(0) {
+            Err(invalid("jobs must be at least 1".to_string()))?
+        }
+        if let Some(ref size) = config.block_size {
+            parse_size(size).map_err(|err| invalid(err.to_string()))?;
+        }
+        if let Some(ref on_error) = config.on_error {
+            OnError::parse(on_error).map_err(|err| invalid(err.to_string()))?;
+        }
+        match config.progress.as_ref().map(String::as_str) {
+            None | Some("auto") | Some("bar") | Some("spinner") | Some("plain") | Some("none") | Some("json") => {},
+            Some(other) => Err(invalid(format!("progress {}, expected auto, bar, spinner, plain, none or json", other)))?,
+        }
+        Ok(config)
+    }
+
+    pub fn defaults(&self) -> Defaults {
+        Defaults {
+            jobs: self.jobs.unwrap_or(DEFAULT_JOBS).to_string(),
+            block_size: self.block_size.clone().unwrap_or_else(|| DEFAULT_BLOCK_SIZE.to_string()),
+            on_error: self.on_error.clone().unwrap_or_else(|| DEFAULT_ON_ERROR.to_string()),
+            progress: self.progress.clone().unwrap_or_else(|| DEFAULT_PROGRESS.to_string()),
+        }
+    }
+
+    /// built-in defaults in place of values missing from file
+    pub fn effective(self) -> Self {
+        let defaults = self.defaults();
+        Config {
+            jobs: Some(self.jobs.unwrap_or(DEFAULT_JOBS)),
+            block_size: Some(defaults.block_size),
+            on_error: Some(defaults.on_error),
+            progress: Some(defaults.progress),
+            exclude: self.exclude,
+        }
+    }
+
+    /// exclude patterns as arguments, appended to command line: its rules are checked first
+    pub fn exclude_args(&self) -> Vec<String> {
+        self.exclude.iter().map(|pattern| format!("--exclude={}", pattern)).collect()
+    }
+}
+
+/// ppcp config --edit or --dump. loaded is what Config::load returned
+pub fn run(matches: &ArgMatches, loaded: Result<Config>) -> Result<()> {
+    if matches.is_present("dump") {
+        print!("{}", toml::to_string(&loaded?.effective()).map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?);
+        return Ok(());
+    }
+    let path = Config::path().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no config dir"))?;
+    if !path.exists() {
+        fs::create_dir_all(path.parent().unwrap_or(&path))?;
+        fs::write(&path, TEMPLATE)?;
+    }
+    // broken file can be fixed with --edit: load error is not fatal here
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+    // "code --wait": program and its arguments
+    let mut words = editor.split_whitespace();
+    let status = Command::new(words.next().unwrap_or("vi")).args(words).arg(&path).status()?;
+    if !status.success() {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{} exited with {}", editor, status)))?
+    }
+    // tell about mistakes now, not on next copy
+    Config::load()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config> {
+        Config::parse(text, Path::new("config.toml"))
+    }
+
+    #[test]
+    fn empty_file_is_built_in_defaults() {
+        let defaults = parse("").unwrap().defaults();
+        assert_eq!(defaults.jobs, DEFAULT_JOBS.to_string());
+        assert_eq!(defaults.block_size, DEFAULT_BLOCK_SIZE);
+        assert_eq!(defaults.on_error, DEFAULT_ON_ERROR);
+        assert_eq!(defaults.progress, DEFAULT_PROGRESS);
+    }
+
+    #[test]
+    fn template_is_valid() {
+        assert!(parse(TEMPLATE).unwrap().exclude.is_empty());
+    }
+
+    #[test]
+    fn file_values_replace_built_in_ones() {
+        let config = parse("jobs = 4\nblock-size = \"1M\"\non-error = \"retry:3\"\nprogress = \"json\"\n").unwrap();
+        let defaults = config.defaults();
+        assert_eq!(defaults.jobs, "4");
+        assert_eq!(defaults.block_size, "1M");
+        assert_eq!(defaults.on_error, "retry:3");
+        assert_eq!(defaults.progress, "json");
+    }
+
+    #[test]
+    fn missing_values_are_filled_in_effective() {
+        let config = parse("jobs = 2\nexclude = [\"*.swp\"]\n").unwrap().effective();
+        assert_eq!(config.jobs, Some(2));
+        assert_eq!(config.block_size.as_ref().map(String::as_str), Some(DEFAULT_BLOCK_SIZE));
+        assert_eq!(config.on_error.as_ref().map(String::as_str), Some(DEFAULT_ON_ERROR));
+        assert_eq!(config.progress.as_ref().map(String::as_str), Some(DEFAULT_PROGRESS));
+        assert_eq!(config.exclude, vec!["*.swp".to_string()]);
+    }
+
+    #[test]
+    fn excludes_become_arguments() {
+        let config = parse("exclude = [\"*.swp\", \"node_modules/**\"]\n").unwrap();
+        assert_eq!(config.exclude_args(), vec!["--exclude=*.swp".to_string(), "--exclude=node_modules/**".to_string()]);
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        for text in ["jobs = 0", "block-size = \"big\"", "on-error = \"never\"", "progress = \"fancy\"",
+                     "unknown = 1", "jobs = \"4\"", "exclude = \"*.swp\""].iter() {
+            match parse(text) {
+                Err(PpcpError::Config { ref path, .. }) => assert_eq!(path, "config.toml"),
+                other => panic!("{}: {:?}", text, other.map(|_| ())),
+            }
+        }
+    }
+}
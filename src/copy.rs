@@ -1,208 +1,1875 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use clap::ArgMatches;
 use std::sync::mpsc::{Sender, Receiver, channel};
 use std::thread;
 use std::fs::{*, self};
 use std::io::{*, self};
-use std::collections::HashSet;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use crate::app::Result;
+use filetime::FileTime;
 
+use crate::util::io_error_message;
+use crate::error::{PpcpError, Result};
+use crate::util::{absolute, parse_size};
+use crate::tokenbucket::TokenBucket;
+use crate::fastcopy;
+use crate::resume::{self, Partial, ResumeState};
+use crate::hash::{Algorithm, Hasher};
+use crate::sources::SourceList;
+use crate::events::{ErrorContext, OperationControl, OperationStatus, SkipReason, StatsChange, WorkerEvent};
 
-#[derive(Clone, PartialEq, Debug)]
-pub enum StatsChange {
-    FileDone, 
-    BytesTotal(u64),
-    Current(PathBuf, u32, u64, u64),
-}
 
-#[derive(Clone, PartialEq, Debug)]
-pub enum OperationStatus {
-    // Running,
-    // Error(String),
-    // Done,
+/// --on-error: what to do when file fails
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OnError {
+    /// ask user
+    Ask,
+    /// go on with the next file
+    Skip,
+    /// copy file again up to N times, then skip it
+    Retry(u32),
+    /// stop, let other workers finish their files
+    Abort,
 }
 
-pub enum OperationControl {
-    // Abort,
-    // Skip,
-    // Retry,
-    // SkipAll,
+impl OnError {
+    /// "skip", "retry:3", "abort" or "ask"
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = || PpcpError::InvalidArgument { name: "on-error", value: s.to_string() };
+        Ok(match s {
+            "ask" => OnError::Ask,
+            "skip" => OnError::Skip,
+            "abort" => OnError::Abort,
+            _ if s.starts_with("retry:") => OnError::Retry(s["retry:".len()..].parse().map_err(|_| invalid())?),
+            _ => Err(invalid())?,
+        })
+    }
 }
 
-#[derive(Debug)]
-pub enum WorkerEvent {
-    Stat(StatsChange),
-    // Status(OperationStatus),
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EntryKind {
+    File,
+    /// copied as link, not its target
+    Symlink,
+    /// created even if empty, its files come separately
+    Dir,
+    /// --special-files: fifo or device node, recreated with mkfifo or mknod. never opened
+    Special,
 }
 
+/// entry found by SourceWalker: source root, path, size, permissions, kind
+pub type SourceFile = (PathBuf, PathBuf, u64, Permissions, EntryKind);
+/// SourceWalker sends error if it could not read some entry
+pub type SourceItem = std::result::Result<SourceFile, ErrorContext>;
+
+/// cp, mv or sync running in background threads
 pub trait Operation {
-    fn search_path(&self) -> Vec<PathBuf>;
+    fn search_path(&self) -> SourceList;
+    /// for summary line: "copied 10 files"
+    fn verb(&self) -> &'static str;
+    fn dry_run(&self) -> bool {
+        false
+    }
 }
 
+/// copy files received from SourceWalker into destination, --jobs workers in parallel
 pub struct OperationCopy {
-    sources: Vec<PathBuf>,
+    sources: SourceList,
+    dry_run: bool,
+    /// copying is done. false if aborted
+    finished: Option<thread::JoinHandle<bool>>,
 }
 
 impl Operation for OperationCopy {
-    fn search_path(&self) -> Vec<PathBuf> {
+    fn search_path(&self) -> SourceList {
         self.sources.clone()
     }
+    fn verb(&self) -> &'static str {
+        if self.dry_run { "would copy" } else { "copied" }
+    }
+    fn dry_run(&self) -> bool {
+        self.dry_run
+    }
 }
 
-#[derive(Fail, Debug)]
-pub enum OperationError {
-    #[fail(display = "Arguments missing")]
-    ArgumentsMissing,
-    #[fail(display = "Can not copy directory {} to file {}", src, dest)]
-    DirOverFile {src: String, dest: String},
+/// resolve source and destination arguments.
+/// returns source paths and canonical destination directory. missing source is an error before anything is done
+fn parse_target(matches: &ArgMatches, dry_run: bool) -> Result<(SourceList, PathBuf)> {
+    let mut source: Vec<PathBuf> = match matches.values_of("source") {
+        Some(files) => files.map(PathBuf::from).collect(),
+        None => Vec::new(),
+    };
+    if matches.is_present("files-from") {
+        return parse_files_from(matches, source, dry_run);
+    }
+    
+    let dest = match (matches.value_of("target-directory"), matches.value_of("dest")) {
+        // -t d a b: clap takes b for dest, it's a source too
+        (Some(dir), last) => {
+            source.extend(last.map(PathBuf::from));
+            let dir = PathBuf::from(dir);
+            if fs::metadata(&dir).map(|m| !m.is_dir()).unwrap_or(false) {
+                Err(PpcpError::InvalidArgument { name: "target-directory", value: format!("{}, not a dir", dir.display()) })?
+            }
+            dir
+        }
+        (None, Some(file)) => PathBuf::from(file),
+        (None, None) => Err(PpcpError::ArgumentsMissing)?,
+    };
+    if source.is_empty() {
+        println!("{:?}", source);
+        Err(PpcpError::ArgumentsMissing)?;
+    }
+    // typo in source fails now, not after the rest is copied
+    let source = SourceList::from_args(source)?;
+    
+    let dest_parent = dest.parent().ok_or_else(|| io::Error::new(io::ErrorKind::Other, "dest.parent?"))?.to_owned();
+    if ! dest_parent.exists() && !dry_run {
+        fs::create_dir_all(&dest_parent)?;
+    }
+    if matches.is_present("no-target-directory") {
+        return exact_target(source, dest, dry_run);
+    }
+    let (dest_is_file, dest_dir) = if !dest.exists() {
+        // if dest not exists - consider it a dir
+        // cp /path/to/dir . -> must create dir and set it as dest
+        // cp /dir1 /dir2 /file . -> cp /dir1/* ./dir; cp /dir2/* ./dir2; cp /file ./
+        (false, dest.clone())
+    }
+    else {
+        let meta = fs::symlink_metadata(&dest)?;
+        if meta.is_file() {
+            // cp /path/to/file.txt ./here/file.txt: dest_dir = ./here
+            (true, dest_parent)
+        }
+        else {
+            // cp /path/to/dir ./here/foo -> copy to/dir/* ./here/foo
+            (false, dest.clone())
+        }
+    };
+    for src in source.iter() {
+        let meta = fs::symlink_metadata(&src)?;
+        if dest_is_file && meta.is_dir() {
+            Err(PpcpError::DirOverFile{src: src.display().to_string(), dest: dest.display().to_string()})?
+        }
+    }
+    if ! dest_is_file && !dest_dir.exists() {
+        if dry_run {
+            // nothing to canonicalize yet
+            return Ok((source, std::env::current_dir()?.join(dest_dir)));
+        }
+        fs::create_dir_all(&dest_dir)?
+    }
+    let dest_dir = dest_dir.canonicalize()?;
+    Ok((source, dest_dir))
 }
 
-impl OperationCopy {
-    pub fn new(matches: &ArgMatches, _user_rx: Receiver<OperationControl>, worker_tx: Sender<WorkerEvent>,
-                src_rx: Receiver<(PathBuf, PathBuf, u64, Permissions, bool)>) -> Result<Self> {
-        let source = match matches.values_of("source") {
-            Some(files) => files.map(PathBuf::from).collect(),
-            None => Vec::new(),
-        };
-        if source.is_empty() {
-            println!("{:?}", source);
-            Err(OperationError::ArgumentsMissing)?;
+/// --files-from: the only source is contents of source root, listed paths are walked by SourceWalker.
+/// clap puts lone positional argument into source, it's the destination
+fn parse_files_from(matches: &ArgMatches, mut args: Vec<PathBuf>, dry_run: bool) -> Result<(SourceList, PathBuf)> {
+    args.extend(matches.value_of("dest").map(PathBuf::from));
+    let dest = match matches.value_of("target-directory") {
+        Some(dir) => PathBuf::from(dir),
+        None => args.pop().ok_or(PpcpError::ArgumentsMissing)?,
+    };
+    if !args.is_empty() {
+        Err(PpcpError::InvalidArgument { name: "files-from", value: format!("{}, sources are read from file", args[0].display()) })?
+    }
+    if fs::metadata(&dest).map(|m| !m.is_dir()).unwrap_or(false) {
+        Err(PpcpError::InvalidArgument { name: "files-from", value: format!("{} is not a dir", dest.display()) })?
+    }
+    // trailing slash: root itself is not created in dest
+    let root = SourceList::new(vec![PathBuf::from(format!("{}/", matches.value_of("source-root").unwrap_or(".")))])?;
+    if !dest.exists() {
+        if dry_run {
+            return Ok((root, std::env::current_dir()?.join(dest)));
         }
-        
-        let dest = match matches.value_of("dest") {
-            Some(file) => PathBuf::from(file),
-            None => Err(OperationError::ArgumentsMissing)?,
-        };
-        
-        let dest_parent = dest.parent().ok_or_else(|| io::Error::new(io::ErrorKind::Other, "dest.parent?"))?.to_owned();
-        if ! dest_parent.exists() {
-            fs::create_dir_all(&dest_parent)?;
-        }
-        let (dest_is_file, dest_dir) = if !dest.exists() {
-            // if dest not exists - consider it a dir
-            // cp /path/to/dir . -> must create dir and set it as dest
-            // cp /dir1 /dir2 /file . -> cp /dir1/* ./dir; cp /dir2/* ./dir2; cp /file ./
-            (false, dest.clone())
+        fs::create_dir_all(&dest)?
+    }
+    Ok((root, dest.canonicalize()?))
+}
+
+/// -T: dest is the copy of the only source, not a dir to copy it into
+fn exact_target(source: SourceList, dest: PathBuf, dry_run: bool) -> Result<(SourceList, PathBuf)> {
+    if source.len() > 1 {
+        Err(PpcpError::InvalidArgument { name: "no-target-directory", value: format!("{} sources, expected one", source.len()) })?
+    }
+    let src_is_dir = fs::metadata(&source[0]).map(|m| m.is_dir()).unwrap_or(false);
+    match fs::symlink_metadata(&dest) {
+        Ok(ref meta) if meta.is_dir() && !src_is_dir => {
+            Err(PpcpError::InvalidArgument { name: "no-target-directory", value: format!("{} is a dir", dest.display()) })?
         }
-        else {
-            let meta = fs::symlink_metadata(&dest)?;
-            if meta.is_file() {
-                // cp /path/to/file.txt ./here/file.txt: dest_dir = ./here
-                (true, dest_parent)
+        Ok(ref meta) if !meta.is_dir() && src_is_dir => {
+            Err(PpcpError::DirOverFile { src: source[0].display().to_string(), dest: dest.display().to_string() })?
+        }
+        _ => {},
+    }
+    if !src_is_dir {
+        // file is created by worker
+        return Ok((source, absolute(&dest)?));
+    }
+    if dry_run && !dest.exists() {
+        return Ok((source, std::env::current_dir()?.join(dest)));
+    }
+    fs::create_dir_all(&dest)?;
+    let dest = dest.canonicalize()?;
+    Ok((source, dest))
+}
+
+/// rsync-like: "src/" copies what is in src, "src" copies src itself.
+/// must be checked before canonicalize, it drops the slash
+fn copies_contents(src: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let s = src.as_os_str().as_bytes();
+    s.len() > 1 && s.ends_with(b"/")
+}
+
+/// where source root src goes in dest_dir: inside it, or with -T or "src/": dest_dir itself
+fn target_of(src: &Path, dest_dir: &Path, contents: bool) -> Option<PathBuf> {
+    if contents {
+        return Some(dest_dir.to_owned());
+    }
+    src.file_name().map(|name| dest_dir.join(name))
+}
+
+/// options of copy workers
+#[derive(Clone, Debug)]
+pub struct CopyOptions {
+    /// mv: remove every source file after it was copied
+    pub remove_source: bool,
+    /// number of parallel copy workers
+    pub jobs: usize,
+    /// only report what would be done, don't touch destination
+    pub dry_run: bool,
+    /// read back every copied file and compare its checksum with source
+    pub verify: bool,
+    /// --checksum-algorithm of --verify
+    pub checksum: Algorithm,
+    /// attributes of source files and dirs to set on copies
+    pub preserve: PreserveOpts,
+    /// don't overwrite existing files
+    pub no_clobber: bool,
+    /// copy only files newer than destination
+    pub update: bool,
+    /// with update: files of the same size are up to date regardless of mtime
+    pub size_only: bool,
+    /// don't copy files identical to existing destination
+    pub skip_existing: Option<SkipExisting>,
+    /// ask what to do with existing destination files
+    pub interactive: bool,
+    /// copy to "name (1).ext" when destination exists
+    pub rename_conflicts: bool,
+    /// replace destination files that can't be opened for writing
+    pub force: bool,
+    /// read and write buffer size of every worker
+    pub block_size: usize,
+    /// retry file this many times on error before asking user
+    pub retries: u32,
+    /// --on-error retry:N retries every error, --retries only transient ones
+    pub retry_any_error: bool,
+    /// copy symlinks as links to the same target, not to source link
+    pub no_dereference: bool,
+    /// print resolved options before start
+    pub verbose: bool,
+    /// continue partial files of interrupted copy
+    pub resume: bool,
+    /// write to temp file, rename it to destination when complete
+    pub atomic: bool,
+    /// max bytes per second of all workers together
+    pub limit_rate: Option<u64>,
+    /// copy by sharing data blocks or inside kernel if filesystem can
+    pub reflink: Reflink,
+    /// leave holes in destination instead of writing zeros
+    pub sparse: Sparse,
+    /// files linked to the same source inode are linked in destination too
+    pub hard_links: bool,
+    /// -T: destination is the copy of source, not a dir to copy it into
+    pub no_target_dir: bool,
+    /// canonical source dirs given with trailing slash, their contents go right into destination
+    pub contents_of: HashSet<PathBuf>,
+}
+
+/// --skip-existing: when destination file is the same as source
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SkipExisting {
+    Size,
+    /// same size and mtime, within MTIME_TOLERANCE
+    SizeMtime,
+    /// same size and hash of contents, both files are read
+    Checksum,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Sparse {
+    /// copy only data extents of sparse source files, holes stay holes
+    Auto,
+    /// also don't write blocks of zeros, leave holes instead
+    Always,
+    /// write holes as zeros
+    Never,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Reflink {
+    /// try reflink, then copy_file_range, then read and write
+    Auto,
+    /// reflink or fail
+    Always,
+    /// read and write
+    Never,
+}
+
+impl CopyOptions {
+    /// options of cp, mv and sync arguments
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let jobs = matches.value_of("jobs").unwrap_or("1").parse::<usize>()?;
+        if jobs == 0 {
+            Err(PpcpError::InvalidArgument{name: "jobs", value: "0".to_string()})?
+        }
+        let on_error = OnError::parse(matches.value_of("on-error").unwrap_or("ask"))?;
+        let block_size = parse_size(matches.value_of("block-size").unwrap_or("128K"))?;
+        // whole pages, odd sizes make every read unaligned
+        if block_size == 0 || block_size % 4096 != 0 {
+            Err(PpcpError::InvalidArgument{name: "block-size", value: block_size.to_string()})?
+        }
+        let mut preserve = PreserveOpts::empty();
+        for attr in matches.values_of("preserve").into_iter().flatten() {
+            preserve |= PreserveOpts::from_attr(attr);
+        }
+        if matches.is_present("archive") {
+            preserve = PreserveOpts::all();
+        }
+        if matches.is_present("preserve-mtime") {
+            preserve |= PreserveOpts::TIMESTAMPS;
+        }
+        if matches.is_present("preserve-mode") {
+            // -p preserves both, like cp
+            preserve |= PreserveOpts::MODE | PreserveOpts::TIMESTAMPS;
+        }
+        // -a --no-preserve=ownership: everything but ownership
+        let mut no_preserve = PreserveOpts::empty();
+        for attr in matches.values_of("no-preserve").into_iter().flatten() {
+            no_preserve |= PreserveOpts::from_attr(attr);
+        }
+        preserve.remove(no_preserve);
+        // acl entries are on top of mode bits, owner, group and other entries are the mode
+        if preserve.contains(PreserveOpts::ACL) {
+            if no_preserve.contains(PreserveOpts::MODE) {
+                Err(PpcpError::InvalidArgument { name: "no-preserve", value: "mode, --preserve=acl needs it".to_string() })?
             }
-            else {
-                // cp /path/to/dir ./here/foo -> copy to/dir/* ./here/foo
-                (false, dest.clone())
+            preserve |= PreserveOpts::MODE;
+        }
+        Ok(CopyOptions {
+            remove_source: false,
+            jobs,
+            dry_run: matches.is_present("dry-run"),
+            verify: matches.is_present("verify"),
+            checksum: match matches.value_of("checksum-algorithm").unwrap_or("blake3") {
+                "sha256" => Algorithm::Sha256,
+                "xxhash" => Algorithm::Xxhash,
+                _ => Algorithm::Blake3,
+            },
+            preserve,
+            no_clobber: matches.is_present("no-clobber"),
+            // files completed before interruption are up to date
+            update: matches.is_present("update") || matches.is_present("resume"),
+            size_only: matches.is_present("size-only"),
+            skip_existing: match matches.value_of("skip-existing") {
+                _ if !matches.is_present("skip-existing") => None,
+                Some("size") => Some(SkipExisting::Size),
+                Some("checksum") => Some(SkipExisting::Checksum),
+                _ => Some(SkipExisting::SizeMtime),
+            },
+            interactive: matches.is_present("interactive"),
+            rename_conflicts: matches.is_present("rename-conflicts"),
+            force: matches.is_present("force"),
+            block_size,
+            retries: match on_error {
+                OnError::Retry(n) => n,
+                _ => matches.value_of("retries").unwrap_or("0").parse()?,
+            },
+            retry_any_error: matches!(on_error, OnError::Retry(_)),
+            // like cp -r
+            no_dereference: !matches.is_present("dereference"),
+            verbose: matches.is_present("verbose"),
+            resume: matches.is_present("resume"),
+            atomic: matches.is_present("atomic"),
+            hard_links: matches.is_present("hard-links"),
+            no_target_dir: matches.is_present("no-target-directory"),
+            // known when sources are
+            contents_of: HashSet::new(),
+            reflink: match matches.value_of("reflink").unwrap_or("auto") {
+                "always" => Reflink::Always,
+                "never" => Reflink::Never,
+                _ => Reflink::Auto,
+            },
+            sparse: match matches.value_of("sparse").unwrap_or("auto") {
+                "always" => Sparse::Always,
+                "never" => Sparse::Never,
+                _ => Sparse::Auto,
+            },
+            limit_rate: match matches.value_of("limit-rate") {
+                Some(rate) => Some(parse_size(rate)? as u64),
+                None => None,
+            },
+        })
+    }
+
+    /// "mode, timestamps" for verbose output
+    fn preserved(&self) -> String {
+        let names: Vec<&str> = PRESERVE_ATTRS.iter().filter(|a| self.preserve.contains(a.1)).map(|a| a.0).collect();
+        if names.is_empty() { "nothing".to_string() } else { names.join(", ") }
+    }
+}
+
+bitflags! {
+    /// attributes copied from source to destination, --preserve
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct PreserveOpts: u8 {
+        const MODE = 0b0001;
+        const TIMESTAMPS = 0b0010;
+        const OWNERSHIP = 0b0100;
+        const XATTRS = 0b1000;
+        /// posix access acl, and default acl of dirs. implies MODE
+        const ACL = 0b1_0000;
+    }
+}
+
+/// names of --preserve values
+const PRESERVE_ATTRS: [(&str, PreserveOpts); 5] = [
+    ("mode", PreserveOpts::MODE),
+    ("timestamps", PreserveOpts::TIMESTAMPS),
+    ("ownership", PreserveOpts::OWNERSHIP),
+    ("xattrs", PreserveOpts::XATTRS),
+    ("acl", PreserveOpts::ACL),
+];
+
+/// xattrs linux keeps posix acls in
+const ACL_ACCESS: &str = "system.posix_acl_access";
+const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+impl PreserveOpts {
+    /// flag for --preserve value, empty for unknown
+    fn from_attr(name: &str) -> Self {
+        PRESERVE_ATTRS.iter().find(|a| a.0 == name).map(|a| a.1).unwrap_or_else(PreserveOpts::empty)
+    }
+}
+
+impl OperationCopy {
+    /// start copying. progress and questions go to worker_tx, answers come from user_rx
+    pub fn new(matches: &ArgMatches, user_rx: Receiver<OperationControl>, worker_tx: Sender<WorkerEvent>,
+                src_rx: Receiver<SourceItem>) -> Result<Self> {
+        let options = CopyOptions::from_matches(matches)?;
+        let (source, dest_dir) = parse_target(matches, options.dry_run)?;
+        Ok(OperationCopy::start(source, dest_dir, options, user_rx, worker_tx, src_rx))
+    }
+
+    /// spawn copy threads
+    fn start(source: SourceList, dest_dir: PathBuf, mut options: CopyOptions, user_rx: Receiver<OperationControl>,
+             worker_tx: Sender<WorkerEvent>, src_rx: Receiver<SourceItem>) -> Self {
+        // walker sends canonical roots
+        options.contents_of = source.iter().filter(|src| copies_contents(src)).filter_map(|src| src.canonicalize().ok()).collect();
+        if options.verbose {
+            eprintln!("preserve: {}", options.preserved());
+            if options.no_dereference {
+                eprintln!("symlinks are copied as links to the same target, see --follow-symlinks-once for sources");
             }
-        };
-        for src in source.iter() {
-            let meta = fs::symlink_metadata(&src)?;
-            if dest_is_file && meta.is_dir() {
-                Err(OperationError::DirOverFile{src: src.display().to_string(), dest: dest.display().to_string()})?
+            else {
+                eprintln!("symlinks are followed, their targets are copied");
             }
         }
-        if ! dest_is_file && !dest_dir.exists() {
-            fs::create_dir_all(&dest_dir)?
+        let (q_tx, q_rx) = channel::<SourceFile>(); // source_path, source_file, total, 
+        let abort = Arc::new(AtomicBool::new(false));
+        let ask = ErrorAsk {
+            worker_tx: worker_tx.clone(),
+            user_rx: Arc::new(Mutex::new(user_rx)),
+        };
+        // workers take files from the queue one by one
+        let q_rx = Arc::new(Mutex::new(q_rx));
+        let state: ResumeState = Arc::new(Mutex::new(HashMap::new()));
+        let partial = Arc::new(if options.resume { resume::load(&dest_dir) } else { HashMap::new() });
+        if !options.dry_run {
+            let (state, dest_dir, keep_tmp) = (state.clone(), dest_dir.clone(), options.resume);
+            ctrlc::set_handler(move || {
+                // files being copied now can be continued with --resume
+                resume::interrupted(&dest_dir, &state.lock().unwrap(), keep_tmp);
+                std::process::exit(130);
+            }).unwrap_or(());
         }
-        let dest_dir = dest_dir.canonicalize()?;
-
-        let (q_tx, q_rx) = channel::<(PathBuf, PathBuf, u64, Permissions, bool)>(); // source_path, source_file, total, 
-        let (d_tx, d_rx) = channel::<(PathBuf, u32, u64, u64)>(); // src_path, chunk, done, total
-        CopyWorker::run(dest_dir, d_tx, q_rx);
+        // shared, so the limit is for the whole operation, not per job
+        let limit = options.limit_rate.filter(|rate| *rate > 0).map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate))));
+        // source inodes seen by all workers
+        let links = if options.hard_links { Some(Arc::new((Mutex::new(HashMap::new()), Condvar::new()))) } else { None };
+        // umask can't be read without setting it. no worker creates files yet
+        let umask = read_umask();
+        let workers: Vec<_> = (0..options.jobs)
+            .map(|_| CopyWorker::run(dest_dir.clone(), options.clone(), abort.clone(), q_rx.clone(), ask.clone(),
+                                     partial.clone(), state.clone(), limit.clone(), links.clone(), umask))
+            .collect();
         // MockCopyWorker::run(dest_dir, d_tx, q_rx);
 
-        {
-        let worker_tx = worker_tx.clone();
-        thread::spawn(move || {
-            for (p, chunk, done, todo) in d_rx.iter() {
-                worker_tx.send(WorkerEvent::Stat(StatsChange::Current(p, chunk, done, todo))).expect("send");
-                if done >= todo {
-                    worker_tx.send(WorkerEvent::Stat(StatsChange::FileDone)).expect("send");
+        let finish_tx = worker_tx.clone();
+        let finish_abort = abort.clone();
+        let (finish_dest, dry_run, keep_tmp) = (dest_dir.clone(), options.dry_run, options.resume);
+        let preserve = options.preserve;
+        let finished = thread::spawn(move || {
+            // dirs are done when all workers are done with files in them
+            let mut dirs = HashMap::new();
+            let mut src_dirs = HashSet::new();
+            for worker in workers {
+                if let Ok((worker_dirs, worker_src_dirs)) = worker.join() {
+                    dirs.extend(worker_dirs);
+                    src_dirs.extend(worker_src_dirs);
+                }
+            }
+            for (dest, (src, meta)) in dirs {
+                if preserve.contains(PreserveOpts::TIMESTAMPS) {
+                    let (atime, mtime) = (FileTime::from_last_access_time(&meta), FileTime::from_last_modification_time(&meta));
+                    if let Err(err) = filetime::set_file_times(&dest, atime, mtime) {
+                        let msg = format!("can't preserve timestamps of {}", io_error_message(&dest, &err));
+                        finish_tx.send(WorkerEvent::Status(OperationStatus::Warning(msg))).expect("send");
+                    }
+                }
+                if preserve.contains(PreserveOpts::OWNERSHIP) {
+                    set_owner(&dest, &meta, &finish_tx);
+                }
+                // mode goes last, read-only dir would not let set anything after
+                if preserve.contains(PreserveOpts::MODE) {
+                    if let Err(err) = fs::set_permissions(&dest, meta.permissions()) {
+                        let msg = format!("can't preserve mode of {}", io_error_message(&dest, &err));
+                        finish_tx.send(WorkerEvent::Status(OperationStatus::Warning(msg))).expect("send");
+                    }
                 }
+                if preserve.contains(PreserveOpts::ACL) {
+                    preserve_acl(&src, &dest, true, &finish_tx);
+                }
+            }
+            for dir in src_dirs {
+                prune_empty_dirs(&dir);
+            }
+            let aborted = finish_abort.load(Ordering::SeqCst);
+            // dry run leaves nothing behind, no state file either
+            if aborted && !dry_run {
+                resume::interrupted(&finish_dest, &state.lock().unwrap(), keep_tmp);
+            }
+            else if !dry_run {
+                resume::remove(&finish_dest);
             }
+            !aborted
         });
-        }
         
         thread::spawn(move || {
             // let mut question = "".to_string();
             // let mut skip_all = true;
-            while let Ok((src, path, size, perm, is_link)) = src_rx.recv() {
+            while let Ok(item) = src_rx.recv() {
+                let (src, path, size, perm, kind) = match item {
+                    Ok(file) => file,
+                    Err(ctx) => {
+                        let (src, reason) = (ctx.src.clone(), format!("{:?} ({})", ctx.err.kind(), ctx.err));
+                        // nothing to retry here, walker has already moved on
+                        match ask.ask(ctx) {
+                            OperationControl::Abort => {
+                                // dropping src_rx and q_tx stops walker and copy worker
+                                abort.store(true, Ordering::SeqCst);
+                                break;
+                            }
+                            // nothing under it is copied, exit status is nonzero
+                            _ => {
+                                worker_tx.send(WorkerEvent::Stat(StatsChange::DirFailed(src, reason))).expect("send");
+                                continue;
+                            }
+                        }
+                    }
+                };
 
-                worker_tx.send(WorkerEvent::Stat(StatsChange::BytesTotal(size))).expect("send");
+                if kind != EntryKind::Dir {
+                    worker_tx.send(WorkerEvent::Stat(StatsChange::BytesTotal(size))).expect("send");
+                }
 
-                q_tx.send((src, path, size, perm, is_link)).expect("send");
+                q_tx.send((src, path, size, perm, kind)).expect("send");
+            }
+            if !abort.load(Ordering::SeqCst) {
+                // walker is done, after all BytesTotal
+                worker_tx.send(WorkerEvent::WalkDone).expect("send");
             }
         });
-        Ok(OperationCopy {
+        OperationCopy {
             sources: source,
+            dry_run: options.dry_run,
+            finished: Some(finished),
+        }
+    }
+}
+
+/// --atomic: "f.txt.ppcp_tmp_1a2b3c4d" next to destination, so rename doesn't cross filesystems
+fn temp_name(dest_file: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    let n = COUNTER.fetch_add(1, Ordering::SeqCst) as u32;
+    let suffix = nanos ^ std::process::id().rotate_left(16) ^ n.wrapping_mul(0x9e37_79b9);
+    let mut name = dest_file.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".ppcp_tmp_{:08x}", suffix));
+    dest_file.with_file_name(name)
+}
+
+/// --atomic: temp file is removed if copy fails before it's renamed into place.
+/// also name claimed for --rename-conflicts, until file is copied to it
+struct TempFile(Option<PathBuf>);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(ref tmp) = self.0 {
+            fs::remove_file(tmp).unwrap_or(());
+        }
+    }
+}
+
+/// rename sources into destination, copy and remove the ones on other filesystem
+pub struct OperationMove {
+    copy: OperationCopy,
+}
+
+impl Operation for OperationMove {
+    fn search_path(&self) -> SourceList {
+        self.copy.search_path()
+    }
+    fn verb(&self) -> &'static str {
+        "moved"
+    }
+}
+
+impl OperationMove {
+    /// rename every source into destination. sources on other filesystem are copied and then removed
+    pub fn new(matches: &ArgMatches, user_rx: Receiver<OperationControl>, worker_tx: Sender<WorkerEvent>,
+                src_rx: Receiver<SourceItem>) -> Result<Self> {
+        if let Some(list) = matches.value_of("files-from") {
+            // listed files are copied into dest root, nothing to rename or mirror
+            Err(PpcpError::InvalidArgument { name: "files-from", value: format!("{}, only supported by cp", list) })?
+        }
+        let mut options = CopyOptions::from_matches(matches)?;
+        let (source, dest_dir) = parse_target(matches, false)?;
+        options.remove_source = true;
+        // like rename does
+        options.preserve |= PreserveOpts::TIMESTAMPS | PreserveOpts::MODE | PreserveOpts::OWNERSHIP;
+        let filtered = ["exclude", "exclude-from", "gitignore", "no-hidden", "min-depth", "max-depth", "min-size", "max-size", "newer-than", "older-than"].iter().any(|arg| matches.is_present(arg));
+        let mut to_copy = Vec::new();
+        for src in source {
+            let contents = options.no_target_dir || copies_contents(&src);
+            let abs = absolute(&src)?;
+            let dest = target_of(&abs, &dest_dir, contents)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "src.file_name?"))?;
+            if dest.is_dir() || (abs.is_dir() && filtered) || ((options.no_clobber || options.update || options.interactive || options.rename_conflicts || options.skip_existing.is_some()) && dest.exists()) {
+                // mv /dir1 d/ where d/dir1 exists: merge contents
+                // excluded files must stay in source: move the rest file by file
+                // rename would overwrite: let worker skip it
+                // as given, trailing slash of src/ tells where it goes
+                to_copy.push(src);
+                continue;
+            }
+            // count before rename, src will be gone after
+            let (files, bytes) = count_files(&abs);
+            match fs::rename(&abs, &dest) {
+                Ok(()) => {
+                    worker_tx.send(WorkerEvent::Stat(StatsChange::Renamed(files, bytes))).expect("send");
+                }
+                // other filesystem
+                Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                    to_copy.push(src);
+                }
+                Err(e) => Err(e)?,
+            }
+        }
+        Ok(OperationMove {
+            copy: OperationCopy::start(SourceList::new(to_copy)?, dest_dir, options, user_rx, worker_tx, src_rx),
+        })
+    }
+}
+
+/// copy new and updated files, with --delete delete destination files missing from source
+pub struct OperationSync {
+    copy: OperationCopy,
+}
+
+impl Operation for OperationSync {
+    fn search_path(&self) -> SourceList {
+        self.copy.search_path()
+    }
+    fn verb(&self) -> &'static str {
+        "synced"
+    }
+}
+
+impl OperationSync {
+    /// copy new and updated files, then with --delete delete files of destination which are not in source
+    pub fn new(matches: &ArgMatches, user_rx: Receiver<OperationControl>, worker_tx: Sender<WorkerEvent>,
+                src_rx: Receiver<SourceItem>) -> Result<Self> {
+        if let Some(list) = matches.value_of("files-from") {
+            // listed files are copied into dest root, nothing to rename or mirror
+            Err(PpcpError::InvalidArgument { name: "files-from", value: format!("{}, only supported by cp", list) })?
+        }
+        if matches.is_present("rename-conflicts") {
+            // every run would add another copy of each updated file
+            Err(PpcpError::InvalidArgument { name: "rename-conflicts", value: "not supported by sync".to_string() })?
+        }
+        let mut options = CopyOptions::from_matches(matches)?;
+        options.update = true;
+        let delete_delay = matches.value_of("delete-delay").unwrap_or("0").parse::<u64>()?;
+        let (source, dest_dir) = parse_target(matches, false)?;
+        // sync /dir1 d/: extra files are deleted from d/dir1 only.
+        // never without --delete: mistyped source would wipe destination
+        let mut mirrors = Vec::new();
+        for src in source.iter().filter(|_| matches.is_present("delete")) {
+            // gone since parse_target: SourceWalker reports it
+            let contents = options.no_target_dir || copies_contents(src);
+            if let Ok(src) = src.canonicalize() {
+                if let (true, Some(dest)) = (src.is_dir(), target_of(&src, &dest_dir, contents)) {
+                    mirrors.push((src.clone(), dest));
+                }
+            }
+        }
+        let delete_tx = worker_tx.clone();
+        let mut copy = OperationCopy::start(source, dest_dir, options, user_rx, worker_tx, src_rx);
+        let finished = copy.finished.take();
+        thread::spawn(move || {
+            // files may be deleted only after everything is copied
+            if !finished.map(|f| f.join().unwrap_or(false)).unwrap_or(false) {
+                return;
+            }
+            for (src, dest) in mirrors {
+                delete_extra(&src, &dest, delete_delay, &delete_tx);
+            }
+        });
+        Ok(OperationSync {
+            copy,
         })
     }
 }
 
+/// sync: files missing from source and not deleted yet because of --delete-delay
+const SYNC_PENDING: &str = ".ppcp-sync-pending";
+
+/// delete files and dirs of dest which are not in src.
+/// with delay, file is deleted only after it is missing from src for delay seconds
+fn delete_extra(src: &Path, dest: &Path, delay: u64, tx: &Sender<WorkerEvent>) {
+    let pending_file = dest.join(SYNC_PENDING);
+    let mut pending = read_pending(&pending_file);
+    let mut still_pending = HashMap::new();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    for entry in walkdir::WalkDir::new(dest).min_depth(1).contents_first(true).into_iter().filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path == pending_file {
+            continue;
+        }
+        let rel = path.strip_prefix(dest).unwrap();
+        match fs::symlink_metadata(src.join(rel)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+            // in source, or can't tell
+            _ => continue,
+        }
+        if entry.file_type().is_dir() {
+            // fails if some files in it are kept, that's fine
+            fs::remove_dir(path).unwrap_or(());
+            continue;
+        }
+        let since = pending.remove(rel).unwrap_or(now);
+        if now.saturating_sub(since) < delay {
+            still_pending.insert(rel.to_owned(), since);
+            continue;
+        }
+        match fs::remove_file(path) {
+            Ok(()) => tx.send(WorkerEvent::Deleted(path.to_owned())).expect("send"),
+            // warned, not counted as deleted
+            Err(err) => {
+                let msg = format!("can't delete {}", io_error_message(path, &err));
+                tx.send(WorkerEvent::Status(OperationStatus::Warning(msg))).expect("send");
+            }
+        }
+    }
+    if let Err(err) = write_pending(&pending_file, &still_pending) {
+        let msg = format!("can't save files pending deletion to {}", io_error_message(&pending_file, &err));
+        tx.send(WorkerEvent::Status(OperationStatus::Warning(msg))).expect("send");
+    }
+}
+
+/// "unix time\tpath" per line: when file was first found missing from source
+fn read_pending(file: &Path) -> HashMap<PathBuf, u64> {
+    let mut pending = HashMap::new();
+    let f = match File::open(file) {
+        Ok(f) => f,
+        Err(_) => return pending,
+    };
+    for line in BufReader::new(f).lines().filter_map(|l| l.ok()) {
+        let mut parts = line.splitn(2, '\t');
+        if let (Some(since), Some(path)) = (parts.next(), parts.next()) {
+            if let Ok(since) = since.parse() {
+                pending.insert(PathBuf::from(path), since);
+            }
+        }
+    }
+    pending
+}
+
+fn write_pending(file: &Path, pending: &HashMap<PathBuf, u64>) -> io::Result<()> {
+    if pending.is_empty() {
+        if file.exists() {
+            fs::remove_file(file)?;
+        }
+        return Ok(());
+    }
+    let mut f = BufWriter::new(File::create(file)?);
+    for (path, since) in pending {
+        writeln!(f, "{}\t{}", since, path.display())?;
+    }
+    f.flush()
+}
+
+/// number of files and total size under p
+fn count_files(p: &Path) -> (u64, u64) {
+    let mut files = 0;
+    let mut bytes = 0;
+    for entry in walkdir::WalkDir::new(p).into_iter().filter_map(|e| e.ok()) {
+        if entry.file_type().is_file() || entry.path_is_symlink() {
+            files += 1;
+            if entry.file_type().is_file() {
+                // symlinks are recreated, not copied, they have no bytes to count
+                bytes += entry.metadata().map(|m| m.len()).unwrap_or(0);
+            }
+        }
+    }
+    (files, bytes)
+}
+
+/// sends error to UI and waits for the answer
+#[derive(Clone)]
+struct ErrorAsk {
+    worker_tx: Sender<WorkerEvent>,
+    user_rx: Arc<Mutex<Receiver<OperationControl>>>,
+}
+
+impl ErrorAsk {
+    fn ask(&self, ctx: ErrorContext) -> OperationControl {
+        self.ask_status(OperationStatus::Error(ctx))
+    }
+
+    /// src would overwrite existing dest
+    fn ask_conflict(&self, src: &Path, dest: &Path) -> OperationControl {
+        self.ask_status(OperationStatus::Conflict(src.to_owned(), dest.to_owned()))
+    }
+
+    fn ask_status(&self, status: OperationStatus) -> OperationControl {
+        // keep the lock until answer is received, so answers are not mixed between workers
+        let user_rx = self.user_rx.lock().unwrap();
+        if self.worker_tx.send(WorkerEvent::Status(status)).is_err() {
+            return OperationControl::Abort;
+        }
+        user_rx.recv().unwrap_or(OperationControl::Abort)
+    }
+}
+
+/// --hard-links: state of source inode with several links
+enum LinkState {
+    /// this link is being copied by some worker, the others wait
+    Copying(PathBuf),
+    /// copied to this destination, other links are linked to it
+    Copied(PathBuf),
+}
+
+/// (dev, inode) of source -> its state. notified when copy is done
+type Links = Arc<(Mutex<HashMap<(u64, u64), LinkState>>, Condvar)>;
+
 struct CopyWorker {
+    dest: PathBuf,
+    options: CopyOptions,
+    ask: ErrorAsk,
+    // progress goes to the same channel as errors, so UI sees them in order
+    tx: Sender<WorkerEvent>,
+    mkdird: HashSet<PathBuf>,
+    /// dest dir -> source dir and its metadata, to set times, mode and acl when all files are copied
+    dirs: HashMap<PathBuf, (PathBuf, Metadata)>,
+    buf: Vec<u8>,
+    /// --resume: files left partial by previous run
+    partial: Arc<HashMap<PathBuf, Partial>>,
+    /// files being copied, saved on interrupt
+    state: ResumeState,
+    limit: Option<Arc<Mutex<TokenBucket>>>,
+    /// set when user aborts on a non-fatal error, stops all workers
+    abort: Arc<AtomicBool>,
+    links: Option<Links>,
+    /// inode of the file being copied, if other workers may wait for it
+    linking: Option<(u64, u64)>,
+    /// process umask, read once before workers start
+    umask: u32,
 }
 
 impl CopyWorker {
-    fn run(dest: PathBuf, tx: Sender<(PathBuf, u32, u64, u64)>, rx: Receiver<(PathBuf, PathBuf, u64, Permissions, bool)>) {
+    /// returns dirs to set timestamps and mode of and source dirs to prune after move
+    fn run(dest: PathBuf, options: CopyOptions, abort: Arc<AtomicBool>, rx: Arc<Mutex<Receiver<SourceFile>>>, ask: ErrorAsk,
+           partial: Arc<HashMap<PathBuf, Partial>>, state: ResumeState, limit: Option<Arc<Mutex<TokenBucket>>>,
+           links: Option<Links>, umask: u32)
+        -> thread::JoinHandle<(HashMap<PathBuf, (PathBuf, Metadata)>, HashSet<PathBuf>)>
+    {
         thread::spawn(move || {
-            let mut mkdird = HashSet::new();
-            for (src, p, sz, perm, is_link) in rx.iter() {
-                let r = if src.is_file() {
-                    p.file_name().unwrap().into()
-                }
-                else {
-                    // cp /dir1 d/
-                    // src = /dir1 p = /dir1/inner/inner2/f.txt
-                    // dest_dir = d/dir1/inner/inner2/f.txt
-                    // diff(/dir1 /dir1/inner/inner2/f.txt) = inner/inner2/f.txt
-                    let p_parent : PathBuf = src.file_name().unwrap().into();
-                    p_parent.join(pathdiff::diff_paths(&p, &src).unwrap())
+            let remove_source = options.remove_source;
+            // throttled: small chunks, so progress moves smoothly instead of one big chunk with a long sleep
+            let buf_size = match options.limit_rate {
+                Some(rate) if rate > 0 => options.block_size.min((rate as usize / 10).max(4096)),
+                _ => options.block_size,
+            };
+            let buf = vec![0; buf_size];
+            let mut worker = CopyWorker {
+                dest,
+                options,
+                tx: ask.worker_tx.clone(),
+                ask: ask.clone(),
+                mkdird: HashSet::new(),
+                dirs: HashMap::new(),
+                buf,
+                partial,
+                state,
+                limit,
+                abort: abort.clone(),
+                links,
+                linking: None,
+                umask,
+            };
+            // source dirs to clean up after move
+            let mut src_dirs = HashSet::new();
+            loop {
+                // lock is released before copying, other workers can take next file
+                let next = rx.lock().unwrap().recv();
+                let (src, p, sz, perm, kind) = match next {
+                    Ok(file) => file,
+                    Err(_) => break,
                 };
-                let dest_file = dest.join(r.clone());
-                let dest_dir = dest_file.parent().unwrap().to_owned();
-                if ! mkdird.contains(&dest_dir) {
-                    // TODO : this will make dir foo/bar/baz and then foo/bar again
-                    fs::create_dir_all(&dest_dir).unwrap();
-                    mkdird.insert(dest_dir.clone());
-                }
-                
-                if is_link {
-                    std::os::unix::fs::symlink(&p, &dest_file).unwrap_or(()); // FIXME 
-                    // std::os::unix::fs::symlink(&p, &dest_file).unwrap(); // FIXME 
-                    tx.send((p, sz as u32, sz, sz)).unwrap();
-                    continue;
-                }
-
-                let fwh = File::create(&dest_file).unwrap();
-                fwh.set_permissions(perm).unwrap_or(()); // works on unix fs only
-
-                let mut fr = BufReader::new(File::open(&p).unwrap());
-                let mut fw = BufWriter::new(fwh);
-                let mut buf = vec![0; 10_000_000];
-                let mut s: u64 = 0;
+                if abort.load(Ordering::SeqCst) {
+                    break;
+                }
+                if remove_source && !src_dirs.contains(&src) && src.is_dir() {
+                    src_dirs.insert(src.clone());
+                }
+                let mut attempt = 0;
                 loop {
-                    match fr.read(&mut buf) {
-                        Ok(ds) => {
-                            s += ds as u64;
-                            if ds == 0 {
-                                break;
-                            }
-                            fw.write_all(&buf[..ds]).unwrap();
-                            tx.send((p.clone(), ds as u32, s, sz)).unwrap();
+                    let mut done = 0;
+                    let err = match worker.copy_file(&src, &p, sz, &perm, kind, &mut done) {
+                        Ok(()) => break,
+                        Err(err) => err,
+                    };
+                    let retry = worker.options.retry_any_error || is_transient(&err);
+                    if attempt < worker.options.retries && retry && !abort.load(Ordering::SeqCst) {
+                        attempt += 1;
+                        worker.tx.send(WorkerEvent::Stat(StatsChange::AutoRetry(done, attempt, worker.options.retries))).expect("send");
+                        // 0.5s, 1s, 2s... capped, so a long --on-error retry:N doesn't wait for hours
+                        thread::sleep(Duration::from_millis(500 << (attempt - 1).min(6)));
+                        continue;
+                    }
+                    let reason = format!("{:?} ({})", err.kind(), err);
+                    let answer = ask.ask(ErrorContext { src: p.clone(), dest: Some(worker.dest_path(&src, &p)), err });
+                    match answer {
+                        OperationControl::Retry => {
+                            worker.tx.send(WorkerEvent::Stat(StatsChange::Retry(done))).expect("send");
                         }
-                        Err(e) => {
-                            println!("{:?}", e);
+                        OperationControl::Abort => {
+                            abort.store(true, Ordering::SeqCst);
+                            break;
+                        }
+                        _ => {
+                            // dirs have no BytesTotal, there is nothing to take off totals
+                            let failed = if kind == EntryKind::Dir {
+                                StatsChange::DirFailed(p.clone(), reason)
+                            }
+                            else {
+                                StatsChange::FileFailed(done, sz, p.clone(), reason)
+                            };
+                            worker.tx.send(WorkerEvent::Stat(failed)).expect("send");
                             break;
                         }
                     }
                 }
+                // not copied, next link waiting for it copies itself
+                worker.link_done(None);
             }
-        });
+            (worker.dirs, src_dirs)
+        })
+    }
+
+    /// dirs of files are created with them, this is for dirs without files.
+    /// mode and times are set after all files, like for the rest of dirs
+    fn make_dir(&mut self, p: &Path, dest_dir: PathBuf) -> io::Result<()> {
+        if self.mkdird.contains(&dest_dir) {
+            return Ok(());
+        }
+        let created = if self.options.dry_run {
+            fs::symlink_metadata(&dest_dir).is_err()
+        }
+        else {
+            match fs::create_dir(&dest_dir) {
+                Ok(()) => true,
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => false,
+                // parent is made by another worker right now, or it's above --min-depth
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                    fs::create_dir_all(&dest_dir)?;
+                    true
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        if !self.options.dry_run && self.options.preserve.intersects(PreserveOpts::TIMESTAMPS | PreserveOpts::MODE | PreserveOpts::OWNERSHIP) {
+            self.dirs.insert(dest_dir.clone(), (p.to_owned(), fs::metadata(p)?));
+        }
+        if created {
+            self.tx.send(WorkerEvent::Stat(StatsChange::DirCreated)).expect("send");
+        }
+        self.mkdird.insert(dest_dir);
+        Ok(())
+    }
+
+    /// where p from source dir src goes
+    fn dest_path(&self, src: &Path, p: &Path) -> PathBuf {
+        if self.options.no_target_dir || self.options.contents_of.contains(src) {
+            // cp -T /dir1 d or cp /dir1/ d: d/inner/f.txt, cp -T f.txt d: d
+            return match pathdiff::diff_paths(&p, &src) {
+                Some(ref rel) if src.is_dir() => self.dest.join(rel),
+                _ => self.dest.clone(),
+            };
+        }
+        let r = if src.is_file() {
+            p.file_name().unwrap().into()
+        }
+        else {
+            // cp /dir1 d/
+            // src = /dir1 p = /dir1/inner/inner2/f.txt
+            // dest_dir = d/dir1/inner/inner2/f.txt
+            // diff(/dir1 /dir1/inner/inner2/f.txt) = inner/inner2/f.txt
+            let p_parent : PathBuf = src.file_name().unwrap().into();
+            p_parent.join(pathdiff::diff_paths(&p, &src).unwrap())
+        };
+        self.dest.join(r)
+    }
+
+    /// copy p from source dir src to destination. done is set to number of bytes copied so far
+    fn copy_file(&mut self, src: &Path, p: &Path, sz: u64, perm: &Permissions, kind: EntryKind, done: &mut u64) -> io::Result<()> {
+        let remove_source = self.options.remove_source;
+        let is_link = kind == EntryKind::Symlink;
+        let started = Instant::now();
+        let mut dest_file = self.dest_path(src, p);
+        if kind == EntryKind::Dir {
+            return self.make_dir(p, dest_file);
+        }
+        // partial file of interrupted copy is continued, it is not an existing file to skip
+        let part = self.partial.get(&dest_file).filter(|part| part.src == p && part.size == sz && !self.options.dry_run);
+        // --atomic: data goes to temp file, it's renamed to destination when complete
+        let resume_tmp = part.and_then(|part| part.tmp.clone());
+        let resume_from = match part {
+            Some(part) => fs::metadata(resume_tmp.as_ref().unwrap_or(&dest_file)).map(|m| m.len().min(part.written)).unwrap_or(0),
+            None => 0,
+        };
+        // checked before dry run, so it reports skipped files as skipped
+        if resume_from == 0 && self.options.no_clobber && fs::symlink_metadata(&dest_file).is_ok() {
+            self.tx.send(WorkerEvent::Stat(StatsChange::FileSkipped(sz, SkipReason::Exists, p.to_owned()))).expect("send");
+            return Ok(());
+        }
+        if resume_from == 0 && self.options.update && is_up_to_date(p, &dest_file, self.options.size_only) {
+            self.tx.send(WorkerEvent::Stat(StatsChange::FileSkipped(sz, SkipReason::UpToDate, p.to_owned()))).expect("send");
+            return Ok(());
+        }
+        if let (0, Some(mode)) = (resume_from, self.options.skip_existing) {
+            if self.is_identical(p, &dest_file, mode, done)? {
+                // checksum: bytes read from source are done already, they stay in totals
+                let size = if mode == SkipExisting::Checksum { 0 } else { sz };
+                self.tx.send(WorkerEvent::Stat(StatsChange::FileSkipped(size, SkipReason::UpToDate, p.to_owned()))).expect("send");
+                return Ok(());
+            }
+        }
+        if self.options.dry_run {
+            if kind == EntryKind::File {
+                // honest preview: file we can't read would fail the real copy too
+                File::open(&p)?;
+            }
+            if fs::symlink_metadata(&dest_file).is_ok() {
+                if self.options.rename_conflicts {
+                    let free = unique_name(&dest_file);
+                    self.tx.send(WorkerEvent::Stat(StatsChange::ConflictRenamed(dest_file, free))).expect("send");
+                }
+                else {
+                    self.tx.send(WorkerEvent::Stat(StatsChange::Conflict(dest_file))).expect("send");
+                }
+            }
+            // a block at a time like a real copy, so bars don't jump to the end
+            loop {
+                let chunk = (sz - *done).min(self.buf.len() as u64);
+                *done += chunk;
+                self.progress(p, chunk, *done, sz);
+                if *done >= sz {
+                    break;
+                }
+            }
+            self.file_done(p);
+            return Ok(());
+        }
+        // empty file of the new name is created right away, it's ours to replace
+        let mut claimed = false;
+        if resume_from == 0 && self.options.interactive && fs::symlink_metadata(&dest_file).is_ok() {
+            match self.ask.ask_conflict(p, &dest_file) {
+                OperationControl::Overwrite => {},
+                OperationControl::KeepBoth => {
+                    dest_file = claim_unique_name(&dest_file)?;
+                    claimed = true;
+                }
+                _ => {
+                    self.tx.send(WorkerEvent::Stat(StatsChange::FileSkipped(sz, SkipReason::Exists, p.to_owned()))).expect("send");
+                    return Ok(());
+                }
+            }
+        }
+        if resume_from == 0 && self.options.rename_conflicts && fs::symlink_metadata(&dest_file).is_ok() {
+            let free = claim_unique_name(&dest_file)?;
+            self.tx.send(WorkerEvent::Stat(StatsChange::ConflictRenamed(dest_file, free.clone()))).expect("send");
+            dest_file = free;
+            claimed = true;
+        }
+        // replaced, not created: counted as updated
+        let existed = resume_from == 0 && !claimed && fs::symlink_metadata(&dest_file).is_ok();
+        let mut claim = TempFile(if claimed { Some(dest_file.clone()) } else { None });
+        self.tx.send(WorkerEvent::Started { src: p.to_owned(), dst: dest_file.clone() }).expect("send");
+        if self.options.preserve.intersects(PreserveOpts::TIMESTAMPS | PreserveOpts::MODE | PreserveOpts::OWNERSHIP) {
+            // remember all dirs from source root to p
+            let mut src_dir = p.parent();
+            let mut dest_dir = dest_file.parent();
+            while let (Some(s), Some(d)) = (src_dir, dest_dir) {
+                if !s.starts_with(src) || self.dirs.contains_key(d) {
+                    break;
+                }
+                // take times now, moving files out of s changes its mtime
+                self.dirs.insert(d.to_owned(), (s.to_owned(), fs::metadata(s)?));
+                src_dir = s.parent();
+                dest_dir = d.parent();
+            }
+        }
+        let dest_dir = dest_file.parent().unwrap().to_owned();
+        if ! self.mkdird.contains(&dest_dir) {
+            // TODO : this will make dir foo/bar/baz and then foo/bar again
+            fs::create_dir_all(&dest_dir)?;
+            self.mkdird.insert(dest_dir);
+        }
+        
+        if is_link {
+            // same target string as source link, relative links stay relative, broken stay broken
+            let target = fs::read_link(&p)?;
+            if let Err(err) = std::os::unix::fs::symlink(&target, &dest_file) {
+                if err.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(err);
+                }
+                // overwrite like regular files
+                fs::remove_file(&dest_file)?;
+                std::os::unix::fs::symlink(&target, &dest_file)?;
+            }
+            claim.0 = None;
+            if self.options.preserve.contains(PreserveOpts::OWNERSHIP) {
+                set_owner(&dest_file, &fs::symlink_metadata(&p)?, &self.tx);
+            }
+            if remove_source {
+                fs::remove_file(&p)?;
+            }
+            self.tx.send(WorkerEvent::Stat(StatsChange::SymlinkCopied)).expect("send");
+            if existed {
+                self.tx.send(WorkerEvent::Stat(StatsChange::Updated)).expect("send");
+            }
+            self.tx.send(WorkerEvent::Finished { src: p.to_owned(), dst: dest_file, bytes: sz, elapsed: started.elapsed() }).expect("send");
+            self.progress(p, sz, sz, sz);
+            self.file_done(p);
+            return Ok(());
+        }
+        if kind == EntryKind::Special {
+            let meta = fs::symlink_metadata(&p)?;
+            if let Err(err) = make_special(&dest_file, &meta) {
+                if err.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(err);
+                }
+                // existing file, or the one claimed for a free name
+                fs::remove_file(&dest_file)?;
+                make_special(&dest_file, &meta)?;
+            }
+            claim.0 = None;
+            if self.options.preserve.contains(PreserveOpts::OWNERSHIP) {
+                set_owner(&dest_file, &meta, &self.tx);
+            }
+            if remove_source {
+                fs::remove_file(&p)?;
+            }
+            self.tx.send(WorkerEvent::Stat(StatsChange::SpecialCopied)).expect("send");
+            if existed {
+                self.tx.send(WorkerEvent::Stat(StatsChange::Updated)).expect("send");
+            }
+            self.tx.send(WorkerEvent::Finished { src: p.to_owned(), dst: dest_file, bytes: 0, elapsed: started.elapsed() }).expect("send");
+            self.progress(p, sz, sz, sz);
+            self.file_done(p);
+            return Ok(());
+        }
+        if let Some(first) = self.wait_link(p)? {
+            if let Err(err) = fs::hard_link(&first, &dest_file) {
+                if err.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(err);
+                }
+                fs::remove_file(&dest_file)?;
+                fs::hard_link(&first, &dest_file)?;
+            }
+            claim.0 = None;
+            if remove_source {
+                fs::remove_file(&p)?;
+            }
+            self.tx.send(WorkerEvent::Stat(StatsChange::HardLinked(sz))).expect("send");
+            // linked, no data written
+            self.tx.send(WorkerEvent::Finished { src: p.to_owned(), dst: dest_file, bytes: 0, elapsed: started.elapsed() }).expect("send");
+            return Ok(());
+        }
+        // mv merging into existing dir: file by file, but on the same filesystem still without copying.
+        // other filesystem, or rename can't replace dest: copy tells why
+        if remove_source && resume_from == 0 && fs::rename(&p, &dest_file).is_ok() {
+            claim.0 = None;
+            self.link_done(Some(dest_file.clone()));
+            self.tx.send(WorkerEvent::Stat(StatsChange::RenamedFile(sz))).expect("send");
+            if existed {
+                self.tx.send(WorkerEvent::Stat(StatsChange::Updated)).expect("send");
+            }
+            self.tx.send(WorkerEvent::Finished { src: p.to_owned(), dst: dest_file, bytes: sz, elapsed: started.elapsed() }).expect("send");
+            return Ok(());
+        }
+
+        let write_path = match resume_tmp {
+            Some(tmp) if resume_from > 0 => tmp,
+            // partial file of non-atomic copy is continued in place
+            _ if resume_from > 0 || !self.options.atomic => dest_file.clone(),
+            _ => temp_name(&dest_file),
+        };
+        let atomic = write_path != dest_file;
+        // like cp: new file gets mode of source, minus umask
+        // with no_clobber file could have appeared since the check, create_new won't overwrite it
+        let fwh = if resume_from > 0 {
+            let mut fwh = OpenOptions::new().write(true).open(&write_path)?;
+            // anything after recorded offset could be written half way
+            fwh.set_len(resume_from)?;
+            fwh.seek(SeekFrom::Start(resume_from))?;
+            fwh
+        }
+        else {
+            let open = || OpenOptions::new().write(true).create(true).truncate(true).create_new(self.options.no_clobber || atomic)
+                .mode(perm.mode()).open(&write_path);
+            match open() {
+                Ok(fwh) => fwh,
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    self.tx.send(WorkerEvent::Stat(StatsChange::FileSkipped(sz, SkipReason::Exists, p.to_owned()))).expect("send");
+                    return Ok(());
+                }
+                // EACCES or EPERM: read-only file, or file of another user. dir is not writable otherwise
+                Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied && fs::symlink_metadata(&write_path).is_ok() => {
+                    if !self.options.force {
+                        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                            format!("destination {} is not writable, --force replaces it", write_path.display())));
+                    }
+                    make_replaceable(&write_path)?;
+                    // once, the error of the second attempt is the one to report
+                    open()?
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        // removed on error, until it's renamed into place
+        let mut tmp = TempFile(if atomic { Some(write_path.clone()) } else { None });
+        // before reading source changes its atime
+        let src_meta = fs::metadata(&p)?;
+        let mut fr = BufReader::new(File::open(&p)?);
+        let mut fw = BufWriter::new(fwh);
+        let mut hasher = self.options.checksum.hasher();
+        if resume_from > 0 {
+            if self.options.verify {
+                // written part is hashed from source, dest is read back in full later anyway
+                let mut prefix = (&mut fr).take(resume_from);
+                loop {
+                    let ds = prefix.read(&mut self.buf)?;
+                    if ds == 0 {
+                        break;
+                    }
+                    hasher.update(&self.buf[..ds]);
+                }
+            }
+            else {
+                fr.seek(SeekFrom::Start(resume_from))?;
+            }
+            *done = resume_from;
+            self.progress(p, resume_from, resume_from, sz);
+        }
+        let part = Partial { src: p.to_owned(), written: *done, size: sz, tmp: tmp.0.clone() };
+        self.state.lock().unwrap().insert(dest_file.clone(), part);
+        // fewer blocks than size: file has holes
+        let sparse_src = resume_from == 0 && self.options.sparse != Sparse::Never && src_meta.blocks() * 512 < sz;
+        let zero_holes = self.options.sparse == Sparse::Always;
+        // copy_file_range would fill holes with zeros, only reflink keeps them
+        let fast = resume_from == 0 && self.copy_fast(fr.get_ref(), fw.get_ref(), p, &write_path, sz, done, sparse_src || zero_holes)?;
+        if fast && self.options.verify {
+            // source was not read, hash it now
+            fr.seek(SeekFrom::Start(0))?;
+            loop {
+                let ds = fr.read(&mut self.buf)?;
+                if ds == 0 {
+                    break;
+                }
+                hasher.update(&self.buf[..ds]);
+            }
+        }
+        // end of source data extent being copied
+        let mut data_end = 0;
+        // reported when file is done, retry starts counting again
+        let mut holes = 0;
+        while !fast {
+            let mut len = self.buf.len();
+            if sparse_src {
+                if *done >= data_end {
+                    // skip hole up to next data, seek leaves it a hole in dest
+                    let (data, end) = fastcopy::next_data(fr.get_ref(), *done)?.unwrap_or((sz, sz));
+                    fr.seek(SeekFrom::Start(data))?;
+                    if data > *done {
+                        fw.seek(SeekFrom::Start(data))?;
+                        if self.options.verify {
+                            hash_zeros(&mut *hasher, data - *done);
+                        }
+                        // hole counts as copied, so percentage is of file size
+                        let hole = data - *done;
+                        holes += hole;
+                        *done = data;
+                        self.progress(p, hole, *done, sz);
+                    }
+                    data_end = end;
+                }
+                len = len.min(data_end.saturating_sub(*done) as usize);
+                if len == 0 {
+                    break;
+                }
+            }
+            let ds = fr.read(&mut self.buf[..len])?;
+            if ds == 0 {
+                break;
+            }
+            if zero_holes && self.buf[..ds].iter().all(|b| *b == 0) {
+                fw.seek(SeekFrom::Current(ds as i64))?;
+                holes += ds as u64;
+            }
+            else {
+                fw.write_all(&self.buf[..ds])?;
+            }
+            if let Some(ref limit) = self.limit {
+                limit.lock().unwrap().consume(ds as u64);
+            }
+            if self.options.verify {
+                hasher.update(&self.buf[..ds]);
+            }
+            *done += ds as u64;
+            if let Some(part) = self.state.lock().unwrap().get_mut(&dest_file) {
+                part.written = *done;
+            }
+            self.progress(p, ds as u64, *done, sz);
+        }
+        fw.flush()?;
+        if !fast && (sparse_src || zero_holes) {
+            // seek past the end does not make file longer, hole at the end needs set_len.
+            // dest is new or truncated, skipped ranges are holes already, nothing to punch
+            fw.get_ref().set_len(*done)?;
+        }
+        if self.options.verify || remove_source {
+            // dest must be on disk before it is checked or source is removed
+            fw.get_ref().sync_all()?;
+        }
+        drop(fw);
+        if self.options.verify && self.dest_hash(&write_path, *done)? != hasher.finalize() {
+            self.tx.send(WorkerEvent::VerifyError(dest_file.clone())).expect("send");
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("checksum mismatch: {}", dest_file.display())));
+        }
+        if self.options.verify {
+            self.tx.send(WorkerEvent::Stat(StatsChange::Verified)).expect("send");
+        }
+        self.state.lock().unwrap().remove(&dest_file);
+        if self.options.preserve.contains(PreserveOpts::OWNERSHIP) {
+            // before chmod: chown clears setuid bits
+            set_owner(&write_path, &src_meta, &self.tx);
+        }
+        let mut xattrs_counted = false;
+        loop {
+            let (result, xattrs_unsupported) = preserve_metadata(p, &src_meta, &write_path, &self.options.preserve, &self.tx);
+            if xattrs_unsupported && !xattrs_counted {
+                // xattrs on FAT and such. not worth asking about every file
+                self.tx.send(WorkerEvent::Stat(StatsChange::XattrsNotPreserved)).expect("send");
+                xattrs_counted = true;
+            }
+            // file is copied anyway, let user decide if it's bad enough to stop
+            let err = match result {
+                Ok(()) => break,
+                Err(err) => err,
+            };
+            let ctx = ErrorContext { src: p.to_owned(), dest: Some(dest_file.clone()), err };
+            match self.ask.ask_status(OperationStatus::MetadataError(ctx)) {
+                OperationControl::Retry => continue,
+                OperationControl::Abort => {
+                    self.abort.store(true, Ordering::SeqCst);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        if claimed && !atomic && !self.options.preserve.contains(PreserveOpts::MODE) {
+            // placeholder was created 0600. mode a new file would get, like cp
+            fs::set_permissions(&write_path, Permissions::from_mode(perm.mode() & 0o7777 & !self.umask))?;
+        }
+        if self.options.preserve.contains(PreserveOpts::ACL) {
+            // after chmod, it would rewrite the mask entry
+            preserve_acl(p, &write_path, false, &self.tx);
+        }
+        if atomic {
+            self.rename_tmp(&write_path, &dest_file, p, &src_meta)?;
+            tmp.0 = None;
+        }
+        claim.0 = None;
+        self.link_done(Some(dest_file.clone()));
+        if remove_source {
+            // never lose data: source is removed only when dest is on disk
+            fs::remove_file(&p)?;
+        }
+        if holes > 0 {
+            self.tx.send(WorkerEvent::Stat(StatsChange::Sparse(holes))).expect("send");
+        }
+        if existed {
+            self.tx.send(WorkerEvent::Stat(StatsChange::Updated)).expect("send");
+        }
+        // not when last chunk is read: file may have grown or shrunk since it was found, and verify may fail yet
+        self.file_done(p);
+        self.tx.send(WorkerEvent::Finished { src: p.to_owned(), dst: dest_file, bytes: *done, elapsed: started.elapsed() }).expect("send");
+        Ok(())
+    }
+
+    /// --atomic: complete file replaces destination at once.
+    /// where rename can't do it, like with a file mounted over destination, it's copied over it
+    fn rename_tmp(&self, tmp: &Path, dest_file: &Path, p: &Path, src_meta: &Metadata) -> io::Result<()> {
+        let err = match fs::rename(tmp, dest_file) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let msg = format!("can't rename {} into place, copied over it: {:?} ({})", tmp.display(), err.kind(), err);
+        self.tx.send(WorkerEvent::Status(OperationStatus::Warning(msg))).expect("send");
+        fs::copy(tmp, dest_file)?;
+        // best effort, temp file had it all
+        preserve_metadata(p, src_meta, dest_file, &self.options.preserve, &self.tx).0.unwrap_or(());
+        fs::remove_file(tmp).unwrap_or(());
+        Ok(())
+    }
+
+    /// reflink or copy_file_range. false if filesystem can't, nothing is copied then.
+    /// clone_only: holes must stay holes, copy_file_range would write them
+    fn copy_fast(&mut self, fr: &File, fw: &File, p: &Path, dest_file: &Path, sz: u64, done: &mut u64, clone_only: bool)
+        -> io::Result<bool>
+    {
+        if self.options.reflink == Reflink::Never {
+            return Ok(false);
+        }
+        match fastcopy::clone_file(fr, fw, dest_file) {
+            Ok(()) => {
+                // no bytes were streamed, file is done at once. empty file is reported by caller
+                self.tx.send(WorkerEvent::Stat(StatsChange::Cloned)).expect("send");
+                *done = sz;
+                if sz > 0 {
+                    self.progress(p, sz, sz, sz);
+                }
+                return Ok(true);
+            }
+            Err(err) => {
+                if self.options.reflink == Reflink::Always {
+                    return Err(err);
+                }
+            }
+        }
+        if clone_only {
+            return Ok(false);
+        }
+        loop {
+            let ds = match fastcopy::copy_range(fr, fw, self.buf.len()) {
+                Ok(ds) => ds,
+                // ENOTSUP, EXDEV on older kernels and so on: fall back to read and write
+                Err(_) if *done == 0 => return Ok(false),
+                Err(err) => return Err(err),
+            };
+            if ds == 0 {
+                break;
+            }
+            if let Some(ref limit) = self.limit {
+                limit.lock().unwrap().consume(ds as u64);
+            }
+            *done += ds as u64;
+            self.progress(p, ds as u64, *done, sz);
+        }
+        // some special files report 0 size copied, read them
+        Ok(*done > 0 || sz == 0)
+    }
+
+    /// --skip-existing: dest_file has the same contents as p, by mode. regular files only.
+    /// checksum progress goes to bytes of source, they are taken back if the file is copied after all
+    fn is_identical(&mut self, p: &Path, dest_file: &Path, mode: SkipExisting, done: &mut u64) -> io::Result<bool> {
+        let (src_meta, dest_meta) = match (fs::symlink_metadata(p), fs::symlink_metadata(dest_file)) {
+            (Ok(src_meta), Ok(dest_meta)) => (src_meta, dest_meta),
+            _ => return Ok(false),
+        };
+        if !src_meta.is_file() || !dest_meta.is_file() || src_meta.len() != dest_meta.len() {
+            return Ok(false);
+        }
+        match mode {
+            SkipExisting::Size => return Ok(true),
+            SkipExisting::SizeMtime => return Ok(match (src_meta.modified(), dest_meta.modified()) {
+                (Ok(src_mtime), Ok(dest_mtime)) => src_mtime <= dest_mtime + MTIME_TOLERANCE && dest_mtime <= src_mtime + MTIME_TOLERANCE,
+                _ => false,
+            }),
+            SkipExisting::Checksum => {},
+        }
+        let total = src_meta.len();
+        let mut hasher = self.options.checksum.hasher();
+        let mut fr = BufReader::new(File::open(p)?);
+        loop {
+            let ds = fr.read(&mut self.buf)?;
+            if ds == 0 {
+                break;
+            }
+            hasher.update(&self.buf[..ds]);
+            *done += ds as u64;
+            // not self.progress: file is done only when it's skipped or copied
+            self.tx.send(WorkerEvent::Stat(StatsChange::Current(p.to_owned(), ds as u64, *done, total))).expect("send");
+        }
+        let src_hash = hasher.finalize();
+        // dest is read as part of the same work, it's not shown on its own
+        let mut hasher = self.options.checksum.hasher();
+        let mut fr = BufReader::new(File::open(dest_file)?);
+        loop {
+            let ds = fr.read(&mut self.buf)?;
+            if ds == 0 {
+                break;
+            }
+            hasher.update(&self.buf[..ds]);
+        }
+        if hasher.finalize() == src_hash {
+            return Ok(true);
+        }
+        self.tx.send(WorkerEvent::Stat(StatsChange::Retry(*done))).expect("send");
+        *done = 0;
+        Ok(false)
+    }
+
+    /// read dest_file back and compute its hash
+    fn dest_hash(&mut self, dest_file: &Path, total: u64) -> io::Result<Vec<u8>> {
+        let mut fr = BufReader::new(File::open(dest_file)?);
+        let mut hasher = self.options.checksum.hasher();
+        let mut done = 0;
+        loop {
+            let ds = fr.read(&mut self.buf)?;
+            if ds == 0 {
+                break;
+            }
+            hasher.update(&self.buf[..ds]);
+            done += ds as u64;
+            self.tx.send(WorkerEvent::Stat(StatsChange::Verify(dest_file.to_owned(), ds as u64, done, total))).expect("send");
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// --hard-links: destination of another link of p if it is copied already.
+    /// waits while other worker copies it. None: p is the first, copy it
+    fn wait_link(&mut self, p: &Path) -> io::Result<Option<PathBuf>> {
+        let links = match self.links {
+            Some(ref links) => links.clone(),
+            None => return Ok(None),
+        };
+        let meta = fs::metadata(p)?;
+        if meta.nlink() < 2 {
+            return Ok(None);
+        }
+        let key = (meta.dev(), meta.ino());
+        let (ref seen, ref copied) = *links;
+        let mut seen = seen.lock().unwrap();
+        loop {
+            match seen.get(&key) {
+                Some(LinkState::Copying(first)) if first != p => seen = copied.wait(seen).unwrap(),
+                Some(LinkState::Copied(dest)) => return Ok(Some(dest.clone())),
+                _ => break,
+            }
+        }
+        seen.insert(key, LinkState::Copying(p.to_owned()));
+        self.linking = Some(key);
+        Ok(None)
+    }
+
+    /// wake up workers waiting for the file being copied. dest is None if it is not copied
+    fn link_done(&mut self, dest: Option<PathBuf>) {
+        if let (Some(key), Some(links)) = (self.linking.take(), self.links.as_ref()) {
+            let (ref seen, ref copied) = **links;
+            let mut seen = seen.lock().unwrap();
+            match dest {
+                Some(dest) => seen.insert(key, LinkState::Copied(dest)),
+                None => seen.remove(&key),
+            };
+            copied.notify_all();
+        }
+    }
+
+    /// chunk of p copied, done of total bytes
+    fn progress(&self, p: &Path, chunk: u64, done: u64, total: u64) {
+        self.tx.send(WorkerEvent::Stat(StatsChange::Current(p.to_owned(), chunk, done, total))).expect("send");
+    }
+
+    /// once per file, when it's complete. done may differ from size if file changed while copied
+    fn file_done(&self, p: &Path) {
+        self.tx.send(WorkerEvent::Stat(StatsChange::FileDone(p.to_owned()))).expect("send");
+    }
+}
+
+/// "file (1).txt", "file (2).txt"... first that does not exist
+fn unique_name(p: &Path) -> PathBuf {
+    let stem = p.file_stem().unwrap_or_default().to_string_lossy();
+    for i in 1.. {
+        let name = match p.extension() {
+            Some(ext) => format!("{} ({}).{}", stem, i, ext.to_string_lossy()),
+            None => format!("{} ({})", stem, i),
+        };
+        let candidate = p.with_file_name(name);
+        if fs::symlink_metadata(&candidate).is_err() {
+            return candidate;
+        }
+    }
+    unreachable!()
+}
+
+/// like unique_name, but the name is taken by creating an empty file with O_EXCL.
+/// another worker or process can't pick the same name between check and copy.
+/// owner only, it must be writable: mode of source is set when the file is copied
+fn claim_unique_name(p: &Path) -> io::Result<PathBuf> {
+    loop {
+        let candidate = unique_name(p);
+        match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&candidate) {
+            Ok(_) => return Ok(candidate),
+            // created since unique_name looked, try the next one
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// process umask. there is no call that only reads it: set and put back at once,
+/// so only while no other thread creates files
+fn read_umask() -> u32 {
+    unsafe {
+        let mask = libc::umask(0o077);
+        libc::umask(mask);
+        mask as u32
+    }
+}
+
+/// mtime of some filesystems (FAT) has 2 seconds resolution
+const MTIME_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// dest exists and is not older than src. if any stat fails, file should be copied.
+/// symlinks are compared by their own mtime, not target's.
+/// if dest is a dir or types of src and dest differ, it is not up to date: copy will report error
+fn is_up_to_date(src: &Path, dest: &Path, size_only: bool) -> bool {
+    let (src_meta, dest_meta) = match (fs::symlink_metadata(src), fs::symlink_metadata(dest)) {
+        (Ok(src_meta), Ok(dest_meta)) => (src_meta, dest_meta),
+        _ => return false,
+    };
+    if dest_meta.is_dir() || src_meta.file_type() != dest_meta.file_type() {
+        return false;
+    }
+    if src_meta.len() == dest_meta.len() && size_only {
+        return true;
+    }
+    match (src_meta.modified(), dest_meta.modified()) {
+        (Ok(src_mtime), Ok(dest_mtime)) => {
+            if dest_mtime > src_mtime + MTIME_TOLERANCE {
+                // dest is newer
+                true
+            }
+            else if src_mtime > dest_mtime + MTIME_TOLERANCE {
+                false
+            }
+            else {
+                // same age: same file, unless sizes differ
+                src_meta.len() == dest_meta.len()
+            }
+        }
+        _ => false,
+    }
+}
+
+/// --force: remove file so it's created again, like cp -f.
+/// in a dir we can't write, make the file writable instead
+fn make_replaceable(path: &Path) -> io::Result<()> {
+    if fs::remove_file(path).is_ok() {
+        return Ok(());
+    }
+    let mut perm = fs::symlink_metadata(path)?.permissions();
+    perm.set_mode(perm.mode() | 0o200);
+    fs::set_permissions(path, perm)
+}
+
+/// error that may go away if file is copied again, like EIO on network fs
+/// whitelist: disk full, read-only fs and such are not worth retrying
+fn is_transient(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        _ => err.raw_os_error() == Some(libc::EIO) || err.raw_os_error() == Some(libc::EAGAIN),
+    }
+}
+
+/// n zero bytes of a hole, as if they were read
+fn hash_zeros(hasher: &mut dyn Hasher, mut n: u64) {
+    let zeros = [0u8; 4096];
+    while n > 0 {
+        let len = n.min(zeros.len() as u64) as usize;
+        hasher.update(&zeros[..len]);
+        n -= len as u64;
+    }
+}
+
+/// set timestamps, xattrs and mode of dst as of src, whichever are in opts.
+/// src_meta is taken before src is read, reading changes atime.
+/// ownership is set by set_owner, it is not an error for non-root.
+/// all are tried. error of times or mode is returned, and apart from it if dst has no xattr support
+fn preserve_metadata(src: &Path, src_meta: &Metadata, dst: &Path, opts: &PreserveOpts, tx: &Sender<WorkerEvent>) -> (io::Result<()>, bool) {
+    let mut err = None;
+    if opts.contains(PreserveOpts::TIMESTAMPS) {
+        let (atime, mtime) = (FileTime::from_last_access_time(src_meta), FileTime::from_last_modification_time(src_meta));
+        err = filetime::set_file_times(dst, atime, mtime).err();
+    }
+    let xattrs_unsupported = opts.contains(PreserveOpts::XATTRS) && copy_xattrs(src, dst, tx).is_err();
+    if opts.contains(PreserveOpts::MODE) {
+        // after xattrs, read-only file would reject them.
+        // exact mode, umask is not applied to chmod
+        if let Err(mode_err) = fs::set_permissions(dst, src_meta.permissions()) {
+            err = err.or(Some(mode_err));
+        }
+    }
+    match err {
+        Some(err) => (Err(err), xattrs_unsupported),
+        None => (Ok(()), xattrs_unsupported),
+    }
+}
+
+/// copy all extended attributes of src to dest. source without xattr support has nothing to copy.
+/// one that fails is a warning and the rest are still copied. dest without xattr support is the error
+fn copy_xattrs(src: &Path, dest: &Path, tx: &Sender<WorkerEvent>) -> io::Result<()> {
+    let names = match xattr::list(src) {
+        Ok(names) => names,
+        Err(_) => return Ok(()),
+    };
+    for name in names {
+        let result = match xattr::get(src, &name) {
+            Ok(Some(value)) => xattr::set(dest, &name, &value),
+            // removed since listed
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => {},
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOTSUP) => return result,
+            Err(err) => {
+                let msg = format!("can't preserve xattr {} of {}", name.to_string_lossy(), io_error_message(dest, &err));
+                tx.send(WorkerEvent::Status(OperationStatus::Warning(msg))).expect("send");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// --preserve=acl: access acl of src, and default acl of dirs, set on dest. mode must be set before.
+/// filesystem without acls is counted, other errors are warnings: file is copied anyway
+fn preserve_acl(src: &Path, dest: &Path, is_dir: bool, tx: &Sender<WorkerEvent>) {
+    let names = if is_dir { &[ACL_ACCESS, ACL_DEFAULT][..] } else { &[ACL_ACCESS][..] };
+    for name in names {
+        let result = match xattr::get(src, name) {
+            Ok(Some(acl)) => xattr::set(dest, name, &acl),
+            // only mode bits, or source filesystem without acls. inherited default acl would add to them
+            Ok(None) => remove_acl(dest, name),
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOTSUP) => remove_acl(dest, name),
+            // source acl is unknown: dest keeps its own
+            Err(err) => {
+                let msg = format!("can't read acl of {}", io_error_message(src, &err));
+                tx.send(WorkerEvent::Status(OperationStatus::Warning(msg))).expect("send");
+                return;
+            }
+        };
+        match result {
+            Ok(()) => {},
+            Err(ref err) if err.raw_os_error() == Some(libc::ENOTSUP) => {
+                tx.send(WorkerEvent::Stat(StatsChange::AclNotPreserved)).expect("send");
+                return;
+            }
+            Err(err) => {
+                let msg = format!("can't preserve acl of {}", io_error_message(dest, &err));
+                tx.send(WorkerEvent::Status(OperationStatus::Warning(msg))).expect("send");
+                return;
+            }
+        }
+    }
+}
+
+/// acl of dest that source doesn't have
+fn remove_acl(dest: &Path, name: &str) -> io::Result<()> {
+    match xattr::get(dest, name) {
+        Ok(Some(_)) => xattr::remove(dest, name),
+        _ => Ok(()),
+    }
+}
+
+/// --special-files: fifo or device node like meta at dest. mode bits go through umask like for new files
+fn make_special(dest: &Path, meta: &Metadata) -> io::Result<()> {
+    let path = CString::new(dest.as_os_str().as_bytes())?;
+    let ret = if meta.file_type().is_fifo() {
+        unsafe { libc::mkfifo(path.as_ptr(), (meta.mode() & 0o7777) as libc::mode_t) }
+    }
+    else {
+        // type bits of mode say char or block, rdev which device
+        unsafe { libc::mknod(path.as_ptr(), meta.mode() as libc::mode_t, meta.rdev() as libc::dev_t) }
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// chown dest to uid and gid from meta, not following symlinks.
+/// non-root can't do it, that's counted and reported once in summary
+fn set_owner(dest: &Path, meta: &Metadata, tx: &Sender<WorkerEvent>) {
+    match std::os::unix::fs::lchown(dest, Some(meta.uid()), Some(meta.gid())) {
+        Ok(()) => {},
+        Err(ref err) if err.raw_os_error() == Some(libc::EPERM) => {
+            tx.send(WorkerEvent::Stat(StatsChange::OwnerNotPreserved)).expect("send");
+        }
+        Err(err) => {
+            let msg = format!("can't preserve ownership of {}", io_error_message(dest, &err));
+            tx.send(WorkerEvent::Status(OperationStatus::Warning(msg))).expect("send");
+        }
+    }
+}
+
+/// remove dir and all its subdirs which are left empty after move.
+/// dirs with files that were not moved are kept
+fn prune_empty_dirs(dir: &Path) {
+    for entry in walkdir::WalkDir::new(dir).contents_first(true).into_iter().filter_map(|e| e.ok()) {
+        if entry.file_type().is_dir() {
+            // fails if not empty, that's fine
+            fs::remove_dir(entry.path()).unwrap_or(());
+        }
     }
 }
 
+#[allow(dead_code)]
 struct MockCopyWorker {}
 
 impl MockCopyWorker {
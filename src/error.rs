@@ -0,0 +1,33 @@
+use std::io;
+use std::num::ParseIntError;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+/// result of ppcp operations
+pub type Result<T> = std::result::Result<T, PpcpError>;
+
+#[derive(Error, Debug)]
+pub enum PpcpError {
+    #[error("Arguments missing")]
+    ArgumentsMissing,
+    #[error("Can not copy directory {src} to file {dest}")]
+    DirOverFile {src: String, dest: String},
+    #[error("Invalid value {value} for {name}")]
+    InvalidArgument {name: &'static str, value: String},
+    /// source in command line does not exist, checked before anything is copied
+    #[error("Source {} not found", .0.display())]
+    SourceNotFound(PathBuf),
+    #[error("{0} errors occurred")]
+    Errors(u64),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    ParseInt(#[from] ParseIntError),
+    /// broken config file
+    #[error("{path}: {message}")]
+    Config {path: String, message: String},
+    /// bad --exclude pattern
+    #[error("{0}")]
+    Pattern(#[from] globset::Error),
+}
@@ -0,0 +1,486 @@
+//! events sent by walker and copy workers to the operation frontend
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use indicatif::HumanBytes;
+
+use crate::util::{format_speed, io_error_message};
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum StatsChange {
+    /// file is copied, sent once for it
+    FileDone(PathBuf),
+    BytesTotal(u64),
+    Current(PathBuf, u64, u64, u64),
+    /// dry run: destination file already exists
+    Conflict(PathBuf),
+    /// same as Current for reading back the destination
+    Verify(PathBuf, u64, u64, u64),
+    /// checksum of destination matched source
+    Verified,
+    /// files, bytes moved with rename, without copying
+    Renamed(u64, u64),
+    /// file of this size, counted in totals already, was moved with rename by worker
+    RenamedFile(u64),
+    /// copied file replaced existing destination file
+    Updated,
+    /// copy failed after this many bytes and will be started again
+    Retry(u64),
+    /// same as Retry, without asking user: bytes done, attempt, max attempts
+    AutoRetry(u64, u32, u32),
+    /// copy failed after this many bytes and file was skipped. size of file, path and error
+    FileFailed(u64, u64, PathBuf, String),
+    /// dir could not be created or walked, or --strict source had no files, and it was skipped.
+    /// path and error. unlike FileFailed nothing is taken off totals, it was never in them
+    DirFailed(PathBuf, String),
+    /// file of this size was not copied. source path
+    FileSkipped(u64, SkipReason, PathBuf),
+    /// file or dir matched --exclude, dir is not walked into
+    FileExcluded,
+    /// file out of --min-size, --max-size, --newer-than or --older-than range. not in totals
+    FileFiltered,
+    /// --no-hidden: dot file or dir, dir is not walked into
+    HiddenSkipped,
+    /// chown failed with EPERM, not root
+    OwnerNotPreserved,
+    /// destination filesystem rejected extended attributes of file
+    XattrsNotPreserved,
+    /// --preserve=acl: destination filesystem has no acls
+    AclNotPreserved,
+    /// --hard-links: file of this size is linked to the copy of another link of it
+    HardLinked(u64),
+    /// file shares data blocks with source, Current for all of it follows
+    Cloned,
+    /// dir did not exist in destination
+    DirCreated,
+    /// -x: dir is on another filesystem than its source, not walked into
+    MountSkipped(PathBuf),
+    /// --files-from: listed path does not exist
+    SourceMissing(PathBuf),
+    /// --sparse: bytes of file left as holes in destination, not written
+    Sparse(u64),
+    /// symlink was recreated as a link, not followed
+    SymlinkCopied,
+    /// fifo or device node was recreated, --special-files
+    SpecialCopied,
+    /// not a regular file, dir or symlink: not sent to be copied, its data can't be read like a file's
+    SpecialSkipped(SpecialKind, PathBuf),
+    /// --rename-conflicts: destination existed, file went to a free name. existing and chosen path
+    ConflictRenamed(PathBuf, PathBuf),
+}
+
+/// type of file which is not regular, dir or symlink
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SpecialKind {
+    /// named pipe: reading it waits for a writer
+    Fifo,
+    /// unix socket, can't be recreated without the program listening on it
+    Socket,
+    /// character or block device
+    Device,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SkipReason {
+    /// --no-clobber
+    Exists,
+    /// --update: destination is not older than source
+    UpToDate,
+}
+
+#[derive(Clone, Debug)]
+pub enum OperationStatus {
+    // Running,
+    /// worker waits for OperationControl answer
+    Error(ErrorContext),
+    /// something went wrong, but file is copied
+    Warning(String),
+    /// --interactive: source, existing destination. worker waits for OperationControl answer
+    Conflict(PathBuf, PathBuf),
+    /// file is copied, but its attributes are not. worker waits for OperationControl answer
+    MetadataError(ErrorContext),
+    // Done,
+}
+
+/// where the error happened, for user to decide what to do
+#[derive(Debug)]
+pub struct ErrorContext {
+    pub src: PathBuf,
+    /// None for errors of reading source tree
+    pub dest: Option<PathBuf>,
+    pub err: io::Error,
+}
+
+/// io::Error is not Clone: the copy keeps os error code, or kind and message
+impl Clone for ErrorContext {
+    fn clone(&self) -> Self {
+        let err = match self.err.raw_os_error() {
+            Some(code) => io::Error::from_raw_os_error(code),
+            None => io::Error::new(self.err.kind(), self.err.to_string()),
+        };
+        ErrorContext { src: self.src.clone(), dest: self.dest.clone(), err }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OperationControl {
+    Abort,
+    Skip,
+    Retry,
+    /// answers to Conflict
+    Overwrite,
+    KeepBoth,
+    SkipFile,
+}
+
+#[derive(Clone, Debug)]
+pub enum WorkerEvent {
+    Stat(StatsChange),
+    Status(OperationStatus),
+    /// destination file differs from source after copy
+    VerifyError(PathBuf),
+    /// sync: file is not in source and was deleted from destination
+    Deleted(PathBuf),
+    /// --scan-first: source files and bytes found so far, nothing is copied yet
+    Scanning(u64, u64),
+    /// all source files are found, totals are final
+    WalkDone,
+    /// file is about to be copied, linked or moved. nothing is sent for skipped files
+    Started { src: PathBuf, dst: PathBuf },
+    /// file is written to destination, elapsed since Started
+    Finished { src: PathBuf, dst: PathBuf, bytes: u64, elapsed: Duration },
+}
+
+impl StatsChange {
+    /// for --progress json, same as --log-file event where there is one
+    pub fn name(&self) -> &'static str {
+        match *self {
+            StatsChange::FileDone(_) => "file_done",
+            StatsChange::BytesTotal(_) => "found",
+            StatsChange::Current(..) => "progress",
+            StatsChange::Conflict(_) => "conflict",
+            StatsChange::Verify(..) => "verify_progress",
+            StatsChange::Verified => "verified",
+            StatsChange::Renamed(..) => "renamed",
+            StatsChange::RenamedFile(_) => "renamed_file",
+            StatsChange::Updated => "updated",
+            StatsChange::Retry(_) => "retry",
+            StatsChange::AutoRetry(..) => "auto_retry",
+            StatsChange::FileFailed(..) => "failed",
+            StatsChange::DirFailed(..) => "dir_failed",
+            StatsChange::FileSkipped(..) => "skipped",
+            StatsChange::FileExcluded => "excluded",
+            StatsChange::FileFiltered => "filtered",
+            StatsChange::HiddenSkipped => "hidden_skipped",
+            StatsChange::OwnerNotPreserved => "owner_not_preserved",
+            StatsChange::XattrsNotPreserved => "xattrs_not_preserved",
+            StatsChange::AclNotPreserved => "acl_not_preserved",
+            StatsChange::HardLinked(_) => "hard_linked",
+            StatsChange::Cloned => "cloned",
+            StatsChange::DirCreated => "dir_created",
+            StatsChange::MountSkipped(_) => "mount_skipped",
+            StatsChange::SourceMissing(_) => "missing",
+            StatsChange::Sparse(_) => "sparse",
+            StatsChange::SymlinkCopied => "symlink_copied",
+            StatsChange::SpecialCopied => "special_copied",
+            StatsChange::SpecialSkipped(..) => "special_skipped",
+            StatsChange::ConflictRenamed(..) => "conflict_renamed",
+        }
+    }
+}
+
+impl WorkerEvent {
+    /// for --progress json, same as --log-file event where there is one
+    pub fn name(&self) -> &'static str {
+        match *self {
+            WorkerEvent::Stat(ref change) => change.name(),
+            WorkerEvent::Status(OperationStatus::Error(_)) => "error",
+            WorkerEvent::Status(OperationStatus::Warning(_)) => "warning",
+            WorkerEvent::Status(OperationStatus::Conflict(..)) => "exists",
+            WorkerEvent::Status(OperationStatus::MetadataError(_)) => "metadata_error",
+            WorkerEvent::VerifyError(_) => "verify_failed",
+            WorkerEvent::Deleted(_) => "deleted",
+            WorkerEvent::Scanning(..) => "scanning",
+            WorkerEvent::WalkDone => "walk_done",
+            WorkerEvent::Started { .. } => "started",
+            WorkerEvent::Finished { .. } => "copied",
+        }
+    }
+}
+
+impl fmt::Display for SpecialKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpecialKind::Fifo => write!(f, "named pipe"),
+            SpecialKind::Socket => write!(f, "socket"),
+            SpecialKind::Device => write!(f, "device node"),
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SkipReason::Exists => write!(f, "destination exists"),
+            SkipReason::UpToDate => write!(f, "destination is up to date"),
+        }
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", io_error_message(&self.src, &self.err))
+    }
+}
+
+impl fmt::Display for StatsChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StatsChange::FileDone(ref p) => write!(f, "{}: done", p.display()),
+            StatsChange::BytesTotal(n) => write!(f, "found file of {}", HumanBytes(n)),
+            StatsChange::Current(ref p, _, done, total) => write!(f, "{}: {}/{}", p.display(), HumanBytes(done), HumanBytes(total)),
+            StatsChange::Conflict(ref p) => write!(f, "{} exists", p.display()),
+            StatsChange::Verify(ref p, _, done, total) => write!(f, "verify {}: {}/{}", p.display(), HumanBytes(done), HumanBytes(total)),
+            StatsChange::Verified => write!(f, "checksum matches"),
+            StatsChange::Renamed(files, bytes) => write!(f, "renamed {} files ({}) without copying", files, HumanBytes(bytes)),
+            StatsChange::RenamedFile(size) => write!(f, "renamed file ({}) without copying", HumanBytes(size)),
+            StatsChange::Updated => write!(f, "replaced existing file"),
+            StatsChange::Retry(done) => write!(f, "retry after {}", HumanBytes(done)),
+            StatsChange::AutoRetry(done, attempt, retries) => write!(f, "retry {}/{} after {}", attempt, retries, HumanBytes(done)),
+            StatsChange::FileFailed(done, size, ref p, ref reason) =>
+                write!(f, "failed {} after {} of {}: {}", p.display(), HumanBytes(done), HumanBytes(size), reason),
+            StatsChange::DirFailed(ref p, ref reason) => write!(f, "failed dir {}: {}", p.display(), reason),
+            StatsChange::FileSkipped(size, reason, ref p) => write!(f, "{}: skipped file of {}, {}", p.display(), HumanBytes(size), reason),
+            StatsChange::FileExcluded => write!(f, "excluded"),
+            StatsChange::FileFiltered => write!(f, "out of size or time range, skipped"),
+            StatsChange::HiddenSkipped => write!(f, "hidden, skipped"),
+            StatsChange::OwnerNotPreserved => write!(f, "ownership not preserved"),
+            StatsChange::XattrsNotPreserved => write!(f, "extended attributes not preserved"),
+            StatsChange::AclNotPreserved => write!(f, "acl not preserved"),
+            StatsChange::HardLinked(size) => write!(f, "hard linked file of {}", HumanBytes(size)),
+            StatsChange::Cloned => write!(f, "cloned with reflink"),
+            StatsChange::DirCreated => write!(f, "created dir"),
+            StatsChange::MountSkipped(ref p) => write!(f, "{}: other filesystem, skipped", p.display()),
+            StatsChange::SourceMissing(ref p) => write!(f, "{}: not found", p.display()),
+            StatsChange::Sparse(bytes) => write!(f, "{} left as holes", HumanBytes(bytes)),
+            StatsChange::SymlinkCopied => write!(f, "copied symlink as link"),
+            StatsChange::SpecialCopied => write!(f, "recreated special file"),
+            StatsChange::SpecialSkipped(kind, ref p) => write!(f, "{}: {}, skipped", p.display(), kind),
+            StatsChange::ConflictRenamed(ref existing, ref free) => write!(f, "{} exists, copied to {}", existing.display(), free.display()),
+        }
+    }
+}
+
+impl fmt::Display for OperationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OperationStatus::Error(ref ctx) => write!(f, "error: {}", ctx),
+            OperationStatus::Warning(ref msg) => write!(f, "warning: {}", msg),
+            OperationStatus::Conflict(ref src, ref dest) => write!(f, "{} exists, source {}", dest.display(), src.display()),
+            OperationStatus::MetadataError(ref ctx) => {
+                let dest = ctx.dest.as_ref().unwrap_or(&ctx.src);
+                write!(f, "can't preserve metadata of {}", io_error_message(dest, &ctx.err))
+            }
+        }
+    }
+}
+
+/// one line for logs, without path of the file for stats that don't have it
+impl fmt::Display for WorkerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WorkerEvent::Stat(ref change) => write!(f, "{}", change),
+            WorkerEvent::Status(ref status) => write!(f, "{}", status),
+            WorkerEvent::VerifyError(ref p) => write!(f, "checksum mismatch: {}", p.display()),
+            WorkerEvent::Deleted(ref p) => write!(f, "deleted {}", p.display()),
+            WorkerEvent::Scanning(files, bytes) => write!(f, "scanning: {} files, {}", files, HumanBytes(bytes)),
+            WorkerEvent::WalkDone => write!(f, "all source files found"),
+            WorkerEvent::Started { ref src, ref dst } => write!(f, "copy {} -> {}", src.display(), dst.display()),
+            WorkerEvent::Finished { ref src, ref dst, bytes, elapsed } =>
+                write!(f, "copied {} -> {}, {} in {:.3}s, {}", src.display(), dst.display(), HumanBytes(bytes), elapsed.as_secs_f64(),
+                       format_speed(bytes, elapsed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::stats::OperationStats;
+
+    /// number of every variant. no wildcard: new variant doesn't compile until it's here and in all_events
+    fn variant(event: &WorkerEvent) -> usize {
+        match *event {
+            WorkerEvent::Stat(ref change) => match *change {
+                StatsChange::FileDone(_) => 0,
+                StatsChange::BytesTotal(_) => 1,
+                StatsChange::Current(..) => 2,
+                StatsChange::Conflict(_) => 3,
+                StatsChange::Verify(..) => 4,
+                StatsChange::Verified => 5,
+                StatsChange::Renamed(..) => 6,
+                StatsChange::RenamedFile(_) => 7,
+                StatsChange::Updated => 8,
+                StatsChange::Retry(_) => 9,
+                StatsChange::AutoRetry(..) => 10,
+                StatsChange::FileFailed(..) => 11,
+                StatsChange::DirFailed(..) => 12,
+                StatsChange::FileSkipped(..) => 13,
+                StatsChange::FileExcluded => 14,
+                StatsChange::FileFiltered => 15,
+                StatsChange::HiddenSkipped => 16,
+                StatsChange::OwnerNotPreserved => 17,
+                StatsChange::XattrsNotPreserved => 18,
+                StatsChange::AclNotPreserved => 19,
+                StatsChange::HardLinked(_) => 20,
+                StatsChange::Cloned => 21,
+                StatsChange::DirCreated => 22,
+                StatsChange::MountSkipped(_) => 23,
+                StatsChange::SourceMissing(_) => 24,
+                StatsChange::Sparse(_) => 25,
+                StatsChange::SymlinkCopied => 26,
+                StatsChange::SpecialCopied => 27,
+                StatsChange::SpecialSkipped(..) => 28,
+                StatsChange::ConflictRenamed(..) => 29,
+            },
+            WorkerEvent::Status(ref status) => match *status {
+                OperationStatus::Error(_) => 30,
+                OperationStatus::Warning(_) => 31,
+                OperationStatus::Conflict(..) => 32,
+                OperationStatus::MetadataError(_) => 33,
+            },
+            WorkerEvent::VerifyError(_) => 34,
+            WorkerEvent::Deleted(_) => 35,
+            WorkerEvent::Scanning(..) => 36,
+            WorkerEvent::WalkDone => 37,
+            WorkerEvent::Started { .. } => 38,
+            WorkerEvent::Finished { .. } => 39,
+        }
+    }
+    const VARIANTS: usize = 40;
+
+    /// one event of every variant. totals come first, so later ones have something to subtract from
+    fn all_events() -> Vec<WorkerEvent> {
+        let p = || PathBuf::from("a/b");
+        let ctx = || ErrorContext { src: p(), dest: Some(p()), err: io::Error::from_raw_os_error(5) };
+        let stats = vec![
+            StatsChange::BytesTotal(100),
+            StatsChange::Current(p(), 10, 10, 100),
+            StatsChange::FileDone(p()),
+            StatsChange::Conflict(p()),
+            StatsChange::Verify(p(), 10, 10, 100),
+            StatsChange::Verified,
+            StatsChange::Renamed(2, 20),
+            StatsChange::RenamedFile(1),
+            StatsChange::Updated,
+            StatsChange::Retry(1),
+            StatsChange::AutoRetry(1, 1, 3),
+            StatsChange::FileFailed(1, 1, p(), "EIO".to_string()),
+            StatsChange::DirFailed(p(), "EACCES".to_string()),
+            StatsChange::FileSkipped(1, SkipReason::UpToDate, p()),
+            StatsChange::FileExcluded,
+            StatsChange::FileFiltered,
+            StatsChange::HiddenSkipped,
+            StatsChange::OwnerNotPreserved,
+            StatsChange::XattrsNotPreserved,
+            StatsChange::AclNotPreserved,
+            StatsChange::HardLinked(1),
+            StatsChange::Cloned,
+            StatsChange::DirCreated,
+            StatsChange::MountSkipped(p()),
+            StatsChange::SourceMissing(p()),
+            StatsChange::Sparse(4096),
+            StatsChange::SymlinkCopied,
+            StatsChange::SpecialCopied,
+            StatsChange::SpecialSkipped(SpecialKind::Fifo, p()),
+            StatsChange::ConflictRenamed(p(), PathBuf::from("a/b (1)")),
+        ];
+        let mut events: Vec<WorkerEvent> = stats.into_iter().map(WorkerEvent::Stat).collect();
+        events.extend(vec![
+            WorkerEvent::Status(OperationStatus::Error(ctx())),
+            WorkerEvent::Status(OperationStatus::Warning("warning".to_string())),
+            WorkerEvent::Status(OperationStatus::Conflict(p(), p())),
+            WorkerEvent::Status(OperationStatus::MetadataError(ctx())),
+            WorkerEvent::VerifyError(p()),
+            WorkerEvent::Deleted(p()),
+            WorkerEvent::Scanning(1, 100),
+            WorkerEvent::WalkDone,
+            WorkerEvent::Started { src: p(), dst: p() },
+            WorkerEvent::Finished { src: p(), dst: p(), bytes: 100, elapsed: Duration::from_millis(10) },
+        ]);
+        events
+    }
+
+    #[test]
+    fn all_variants_are_listed() {
+        let events = all_events();
+        let seen: HashSet<usize> = events.iter().map(variant).collect();
+        assert_eq!(events.len(), VARIANTS);
+        assert_eq!(seen.len(), VARIANTS);
+    }
+
+    #[test]
+    fn names_are_unique() {
+        let events = all_events();
+        let names: HashSet<&str> = events.iter().map(WorkerEvent::name).collect();
+        assert_eq!(names.len(), events.len());
+    }
+
+    #[test]
+    fn every_event_has_log_line() {
+        for event in all_events() {
+            assert!(!event.to_string().is_empty(), "{:?}", event);
+            assert!(!format!("{:?}", event.clone()).is_empty());
+        }
+    }
+
+    #[test]
+    fn stats_take_every_event() {
+        let mut stats = OperationStats::default();
+        for event in all_events() {
+            stats.update(&event);
+        }
+        // the failed file and dir and the missing source, not error events
+        assert_eq!(stats.errors(), 3);
+        assert_eq!(stats.warnings.len(), 1);
+        assert_eq!(stats.files_deleted, 1);
+        assert!(stats.walk_done);
+    }
+
+    #[test]
+    fn retried_error_is_not_counted() {
+        let p = PathBuf::from("a");
+        let ctx = || ErrorContext { src: p.clone(), dest: None, err: io::Error::from_raw_os_error(5) };
+        let mut stats = OperationStats::default();
+        stats.update(&WorkerEvent::Stat(StatsChange::BytesTotal(10)));
+        stats.update(&WorkerEvent::Stat(StatsChange::Current(p.clone(), 5, 5, 10)));
+        stats.update(&WorkerEvent::Status(OperationStatus::Error(ctx())));
+        stats.update(&WorkerEvent::Stat(StatsChange::Retry(5)));
+        stats.update(&WorkerEvent::Stat(StatsChange::Current(p.clone(), 5, 5, 10)));
+        stats.update(&WorkerEvent::Stat(StatsChange::AutoRetry(5, 1, 3)));
+        stats.update(&WorkerEvent::Stat(StatsChange::Current(p.clone(), 10, 10, 10)));
+        stats.update(&WorkerEvent::Stat(StatsChange::FileDone(p.clone())));
+        assert_eq!(stats.errors(), 0);
+        // skipped after asking: it failed
+        stats.update(&WorkerEvent::Stat(StatsChange::BytesTotal(10)));
+        stats.update(&WorkerEvent::Status(OperationStatus::Error(ctx())));
+        stats.update(&WorkerEvent::Stat(StatsChange::FileFailed(0, 10, p.clone(), "EIO".to_string())));
+        assert_eq!(stats.errors(), 1);
+    }
+
+    #[test]
+    fn failed_dir_is_not_taken_off_totals() {
+        let mut stats = OperationStats::default();
+        // dir comes before the files in it, totals are still 0
+        stats.update(&WorkerEvent::Stat(StatsChange::DirFailed(PathBuf::from("a"), "EACCES".to_string())));
+        assert_eq!(*stats.files_total, 0);
+        assert_eq!(*stats.bytes_total, 0);
+        assert_eq!(stats.failed.len(), 1);
+        stats.update(&WorkerEvent::Stat(StatsChange::BytesTotal(10)));
+        assert_eq!(*stats.files_total, 1);
+    }
+}
@@ -0,0 +1,89 @@
+//! copy inside the kernel, without reading file into userspace
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// FICLONE ioctl: _IOW(0x94, 9, int)
+#[cfg(target_os = "linux")]
+const FICLONE: u64 = 0x4004_9409;
+
+/// make dest share all data blocks with src. only on CoW filesystems like btrfs or xfs
+#[cfg(target_os = "linux")]
+pub fn clone_file(src: &File, dest: &File, _dest_path: &Path) -> io::Result<()> {
+    let ret = unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE as _, src.as_raw_fd()) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// copy up to len bytes from current position of src to current position of dest.
+/// returns number of bytes copied, 0 at the end of src
+#[cfg(target_os = "linux")]
+pub fn copy_range(src: &File, dest: &File, len: usize) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::copy_file_range(src.as_raw_fd(), std::ptr::null_mut(), dest.as_raw_fd(), std::ptr::null_mut(), len, 0)
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// start and end of the first data extent at or after pos.
+/// None if there is only hole after pos
+#[cfg(target_os = "linux")]
+pub fn next_data(f: &File, pos: u64) -> io::Result<Option<(u64, u64)>> {
+    // moves file offset, caller seeks where it needs after
+    let data = unsafe { libc::lseek(f.as_raw_fd(), pos as libc::off_t, libc::SEEK_DATA) };
+    if data == -1 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENXIO) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    // end of file counts as a hole, so there is always one after data
+    let hole = unsafe { libc::lseek(f.as_raw_fd(), data, libc::SEEK_HOLE) };
+    if hole == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some((data as u64, hole as u64)))
+}
+
+/// clonefile on APFS. it only makes new files, so clone goes next to dest and replaces it.
+/// dest handle is left with the replaced empty file
+#[cfg(target_os = "macos")]
+pub fn clone_file(src: &File, _dest: &File, dest_path: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut tmp = dest_path.as_os_str().to_owned();
+    tmp.push(".ppcp-clone");
+    let tmp_c = CString::new(tmp.as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let ret = unsafe { libc::fclonefileat(src.as_raw_fd(), libc::AT_FDCWD, tmp_c.as_ptr(), 0) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    std::fs::rename(&tmp, dest_path).map_err(|err| {
+        std::fs::remove_file(&tmp).unwrap_or(());
+        err
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn clone_file(_src: &File, _dest: &File, _dest_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "reflink is supported on linux and macos only"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn copy_range(_src: &File, _dest: &File, _len: usize) -> io::Result<usize> {
+    Err(io::Error::new(io::ErrorKind::Other, "copy_file_range is supported on linux only"))
+}
+
+/// holes are not known, everything is data
+#[cfg(not(target_os = "linux"))]
+pub fn next_data(_f: &File, pos: u64) -> io::Result<Option<(u64, u64)>> {
+    Ok(Some((pos, std::u64::MAX)))
+}
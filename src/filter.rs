@@ -0,0 +1,227 @@
+use std::io::{self, BufRead};
+use std::path::Path;
+
+use clap::ArgMatches;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::util::io_error_message;
+use crate::error::{PpcpError, Result};
+
+/// what walker does with a file or dir
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Verdict {
+    Copy,
+    Exclude,
+    /// dir is excluded, but some --include may match files inside it. walked, not created
+    Descend,
+    /// no rule matched
+    Unmatched,
+}
+
+struct Rule {
+    include: bool,
+    /// trailing slash: matches dirs only
+    dir_only: bool,
+    /// leading slash or slash inside: matches path from source root only, not file name
+    rel_only: bool,
+    /// pattern up to first wildcard. dirs it can't be inside of are pruned
+    prefix: String,
+}
+
+/// --exclude, --include rules in command line order, like rsync: first matching rule wins
+pub struct Filter {
+    /// one automaton for all patterns, not a loop over them for every entry
+    set: GlobSet,
+    /// of every glob in set
+    rules: Vec<Rule>,
+}
+
+impl Filter {
+    pub fn new(matches: &ArgMatches) -> Result<Self> {
+        // (position in command line, include, pattern, where it's from for errors).
+        // patterns of a file share its position
+        let mut patterns: Vec<(usize, bool, String, String)> = Vec::new();
+        for &(arg, include) in [("exclude", false), ("include", true)].iter() {
+            if let (Some(values), Some(indices)) = (matches.values_of(arg), matches.indices_of(arg)) {
+                patterns.extend(indices.zip(values).map(|(i, p)| (i, include, p.to_owned(), format!("--{}", arg))));
+            }
+        }
+        let stdin_files = ["exclude-from", "include-from", "files-from"].iter()
+            .filter_map(|arg| matches.values_of(arg))
+            .flat_map(|files| files)
+            .filter(|file| *file == "-")
+            .count();
+        if stdin_files > 1 {
+            Err(PpcpError::InvalidArgument { name: "exclude-from", value: "- given more than once, stdin can be read once".to_string() })?
+        }
+        for &(arg, include) in [("exclude-from", false), ("include-from", true)].iter() {
+            if let (Some(files), Some(indices)) = (matches.values_of(arg), matches.indices_of(arg)) {
+                for (i, file) in indices.zip(files) {
+                    let lines = read_patterns(arg, file)?;
+                    patterns.extend(lines.into_iter().map(|(n, p)| (i, include, p, format!("{}:{}", file, n))));
+                }
+            }
+        }
+        // stable: file patterns keep their order
+        patterns.sort_by_key(|p| p.0);
+        let mut set = GlobSetBuilder::new();
+        let mut rules = Vec::new();
+        for &(_, include, ref p, ref from) in patterns.iter() {
+            // checked before walker starts, bad line is easy to find
+            let glob = |p: &str| Glob::new(p).map_err(|err| PpcpError::InvalidArgument {
+                name: "pattern",
+                value: format!("{}: {}", from, err),
+            });
+            // rsync-style: trailing slash matches only dirs, --include '*/' walks all of them
+            let dir_only = p.len() > 1 && p.ends_with('/');
+            let p = if dir_only { &p[..p.len() - 1] } else { &p[..] };
+            let anchored = p.len() > 1 && p.starts_with('/');
+            let p = if anchored { &p[1..] } else { p };
+            let rel_only = anchored || p.contains('/');
+            let prefix = p[..p.find(|c| "*?[{\\".contains(c)).unwrap_or_else(|| p.len())].to_owned();
+            set.add(glob(p)?);
+            rules.push(Rule { include, dir_only, rel_only, prefix: prefix.clone() });
+            if p.ends_with("/**") {
+                // node_modules/** matches node_modules dir itself, so excluded one is not walked at all
+                set.add(glob(&p[..p.len() - 3])?);
+                rules.push(Rule { include, dir_only, rel_only, prefix });
+            }
+        }
+        Ok(Filter { set: set.build()?, rules })
+    }
+
+    /// rel is path from source root
+    pub fn check(&self, rel: &Path, is_dir: bool) -> Verdict {
+        if self.rules.is_empty() {
+            return Verdict::Unmatched;
+        }
+        let mut matched = self.set.matches(rel);
+        if let Some(name) = rel.file_name() {
+            matched.extend(self.set.matches(name).into_iter().filter(|&i| !self.rules[i].rel_only));
+        }
+        match matched.into_iter().filter(|&i| is_dir || !self.rules[i].dir_only).min() {
+            Some(i) if self.rules[i].include => Verdict::Copy,
+            Some(_) if is_dir && self.may_include_under(rel) => Verdict::Descend,
+            Some(_) => Verdict::Exclude,
+            None => Verdict::Unmatched,
+        }
+    }
+
+    /// some include rule could match a file in dir, before or after the rule that excludes dir.
+    /// not exact: dir is walked in vain sometimes, but is never pruned wrongly
+    fn may_include_under(&self, dir: &Path) -> bool {
+        let dir = format!("{}/", dir.display());
+        self.rules.iter().filter(|rule| rule.include && !rule.dir_only).any(|rule| {
+            // file name matches at any depth. path from root must start with the prefix
+            !rule.rel_only || rule.prefix.starts_with(&dir) || dir.starts_with(&rule.prefix)
+        })
+    }
+}
+
+/// glob patterns from file, "-" is stdin. one per line, with line numbers.
+/// empty lines and # comments are ignored
+fn read_patterns(arg: &'static str, file: &str) -> Result<Vec<(usize, String)>> {
+    let stdin = io::stdin();
+    let reader: Box<dyn BufRead> = if file == "-" {
+        Box::new(stdin.lock())
+    }
+    else {
+        match std::fs::File::open(file) {
+            Ok(f) => Box::new(io::BufReader::new(f)),
+            Err(err) => Err(PpcpError::InvalidArgument{name: arg, value: io_error_message(Path::new(file), &err)})?,
+        }
+    };
+    let mut patterns = Vec::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        patterns.push((n + 1, line));
+    }
+    Ok(patterns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{App, Arg};
+
+    /// filter of --exclude and --include args, as in command line
+    fn filter(args: &[&str]) -> Filter {
+        let arg = |name| Arg::with_name(name).long(name).takes_value(true).multiple(true).number_of_values(1);
+        let matches = App::new("ppcp")
+            .args(&[arg("exclude"), arg("include")])
+            .get_matches_from(Some("ppcp").into_iter().chain(args.iter().cloned()));
+        Filter::new(&matches).unwrap()
+    }
+
+    fn check(filter: &Filter, rel: &str, is_dir: bool) -> Verdict {
+        filter.check(Path::new(rel), is_dir)
+    }
+
+    #[test]
+    fn no_rules() {
+        assert_eq!(check(&filter(&[]), "a", false), Verdict::Unmatched);
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let f = filter(&["--include", "*.rs", "--exclude", "*"]);
+        assert_eq!(check(&f, "main.rs", false), Verdict::Copy);
+        assert_eq!(check(&f, "readme.md", false), Verdict::Exclude);
+        let f = filter(&["--exclude", "*", "--include", "*.rs"]);
+        assert_eq!(check(&f, "main.rs", false), Verdict::Exclude);
+    }
+
+    #[test]
+    fn anchored_matches_from_root_only() {
+        let f = filter(&["--exclude", "/build"]);
+        assert_eq!(check(&f, "build", true), Verdict::Exclude);
+        assert_eq!(check(&f, "src/build", true), Verdict::Unmatched);
+        // unanchored matches name at any depth
+        let f = filter(&["--exclude", "build"]);
+        assert_eq!(check(&f, "src/build", true), Verdict::Exclude);
+    }
+
+    #[test]
+    fn trailing_slash_matches_dirs_only() {
+        let f = filter(&["--exclude", "tmp/"]);
+        assert_eq!(check(&f, "tmp", true), Verdict::Exclude);
+        assert_eq!(check(&f, "tmp", false), Verdict::Unmatched);
+    }
+
+    #[test]
+    fn double_star() {
+        let f = filter(&["--exclude", "node_modules/**"]);
+        // dir itself too, so it's not walked
+        assert_eq!(check(&f, "node_modules", true), Verdict::Exclude);
+        assert_eq!(check(&f, "node_modules/a/b.js", false), Verdict::Exclude);
+        assert_eq!(check(&f, "web/node_modules", true), Verdict::Unmatched);
+        let f = filter(&["--exclude", "**/*.o"]);
+        assert_eq!(check(&f, "a/b/c.o", false), Verdict::Exclude);
+        assert_eq!(check(&f, "a/b/c.rs", false), Verdict::Unmatched);
+    }
+
+    #[test]
+    fn excluded_dir_is_descended_for_later_include() {
+        let f = filter(&["--exclude", "target", "--include", "*.rs"]);
+        assert_eq!(check(&f, "target", true), Verdict::Descend);
+        assert_eq!(check(&f, "target/a.rs", false), Verdict::Copy);
+        // file of the same name is not walked
+        assert_eq!(check(&f, "target", false), Verdict::Exclude);
+        // dir-only include can't match a file inside
+        let f = filter(&["--exclude", "target", "--include", "*/"]);
+        assert_eq!(check(&f, "target", true), Verdict::Exclude);
+    }
+
+    #[test]
+    fn anchored_include_descends_along_its_prefix() {
+        let f = filter(&["--include", "/src/keep/*.rs", "--exclude", "*"]);
+        assert_eq!(check(&f, "src", true), Verdict::Descend);
+        assert_eq!(check(&f, "src/keep", true), Verdict::Descend);
+        assert_eq!(check(&f, "src/keep/a.rs", false), Verdict::Copy);
+        assert_eq!(check(&f, "src/other", true), Verdict::Exclude);
+        assert_eq!(check(&f, "docs", true), Verdict::Exclude);
+    }
+}
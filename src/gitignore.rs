@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+/// --gitignore: rules of .ignore and .gitignore files, .git/info/exclude and global excludes file,
+/// like git and ripgrep apply them
+pub struct GitIgnores {
+    /// rules by dir they apply to, read once per dir. empty if dir has none
+    dirs: HashMap<PathBuf, Vec<Gitignore>>,
+    /// core.excludesFile, ~/.config/git/ignore by default
+    global: Gitignore,
+}
+
+impl GitIgnores {
+    pub fn new() -> Self {
+        // broken lines are skipped, the rest still works
+        let (global, _) = Gitignore::global();
+        GitIgnores { dirs: HashMap::new(), global }
+    }
+
+    /// nearest ignore file decides first, up to repository root. path is absolute
+    pub fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        // git never tracks its own dir, --include .git/ brings it back
+        if path.file_name().map_or(false, |name| name == ".git") {
+            return true;
+        }
+        for dir in path.ancestors().skip(1) {
+            for rules in self.rules_of(dir).iter() {
+                match rules.matched(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {},
+                }
+            }
+            if dir.join(".git").exists() {
+                break;
+            }
+        }
+        self.global.matched(path, is_dir).is_ignore()
+    }
+
+    fn rules_of(&mut self, dir: &Path) -> &Vec<Gitignore> {
+        self.dirs.entry(dir.to_owned()).or_insert_with(|| {
+            let mut rules = Vec::new();
+            // .ignore wins over .gitignore of the same dir, like in ripgrep
+            for name in [".ignore", ".gitignore"].iter() {
+                let file = dir.join(name);
+                if file.is_file() {
+                    rules.push(Gitignore::new(&file).0);
+                }
+            }
+            // repository root. patterns of info/exclude are relative to it, not to .git/info
+            let exclude = dir.join(".git").join("info").join("exclude");
+            if exclude.is_file() {
+                let mut builder = GitignoreBuilder::new(dir);
+                builder.add(&exclude);
+                if let Ok(exclude) = builder.build() {
+                    rules.push(exclude);
+                }
+            }
+            rules
+        })
+    }
+}
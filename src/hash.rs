@@ -0,0 +1,56 @@
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+/// checksum of file contents for --verify, fed chunk by chunk as file is read
+pub trait Hasher: Send {
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+/// --checksum-algorithm
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Algorithm {
+    Sha256,
+    /// not cryptographic, fastest. 128 bit, collisions by chance are still out of question
+    Xxhash,
+    /// several times faster than SHA-256 on big files
+    Blake3,
+}
+
+impl Algorithm {
+    /// new hasher for every file
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            Algorithm::Sha256 => Box::new(Sha256::new()),
+            Algorithm::Xxhash => Box::new(Xxh3::new()),
+            Algorithm::Blake3 => Box::new(blake3::Hasher::new()),
+        }
+    }
+}
+
+impl Hasher for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        Digest::finalize(*self).to_vec()
+    }
+}
+
+impl Hasher for Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        Xxh3::update(self, data);
+    }
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.digest128().to_be_bytes().to_vec()
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        blake3::Hasher::finalize(&self).as_bytes().to_vec()
+    }
+}
@@ -0,0 +1,45 @@
+//! parallel copy with progress: walks sources, copies files in worker threads
+//! and reports progress as `WorkerEvent`s, for ppcp binary or any other frontend
+
+extern crate clap;
+extern crate walkdir;
+extern crate indicatif;
+extern crate pathdiff;
+extern crate sha2;
+extern crate xxhash_rust;
+extern crate blake3;
+extern crate filetime;
+extern crate globset;
+extern crate glob;
+extern crate ignore;
+#[macro_use] extern crate serde_json;
+extern crate thiserror;
+extern crate xattr;
+extern crate ctrlc;
+extern crate libc;
+#[macro_use] extern crate bitflags;
+#[macro_use] extern crate serde;
+
+pub mod error;
+pub mod copy;
+pub mod events;
+pub mod stats;
+pub mod walker;
+pub mod sources;
+pub mod avgspeed;
+pub mod trackchange;
+pub mod oplog;
+pub mod hash;
+pub mod util;
+mod tokenbucket;
+mod fastcopy;
+mod gitignore;
+mod filter;
+mod resume;
+
+pub use copy::{OperationCopy, OperationMove, OperationSync, Operation};
+pub use events::{WorkerEvent, StatsChange, OperationControl, OperationStatus};
+pub use stats::{OperationStats, Summary};
+pub use walker::SourceWalker;
+pub use sources::SourceList;
+pub use trackchange::TrackChange;
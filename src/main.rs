@@ -1,37 +1,407 @@
 extern crate clap;
-#[macro_use] extern crate failure;
-extern crate walkdir;
 extern crate indicatif;
-extern crate pathdiff;
 extern crate path_abs;
+#[macro_use] extern crate serde_json;
+#[macro_use] extern crate serde;
+extern crate toml;
+extern crate dirs;
+extern crate atty;
+extern crate ppcp;
 
-use clap::{Arg, App};
-
-use std::error;
+use clap::{Arg, App, AppSettings, SubCommand};
+use ppcp::{copy, error, util};
 
 mod app;
-mod copy;
-mod avgspeed;
+mod config;
+mod progress;
+
+/// arguments shared by cp and mv. defaults are from config file
+fn operation_args<'a, 'b>(defaults: &'a config::Defaults) -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("source")
+             .index(1)
+             .required_unless("files-from")
+             .help("source path")
+             .multiple(true),
+        Arg::with_name("dest")
+             .required_unless("target-directory")
+             .help("destination path")
+             .multiple(false),
+        Arg::with_name("target-directory")
+             .short("t")
+             .long("target-directory")
+             .takes_value(true)
+             .value_name("DIR")
+             .conflicts_with("no-target-directory")
+             .help("copy all sources into DIR, like with xargs: ... | xargs ppcp cp -t DIR"),
+        Arg::with_name("no-target-directory")
+             .short("T")
+             .long("no-target-directory")
+             .help("destination is the copy of the only source, not a dir to copy it into"),
+        Arg::with_name("jobs")
+             .short("j")
+             .long("jobs")
+             .takes_value(true)
+             .value_name("N")
+             .default_value(&defaults.jobs)
+             .help("number of files copied in parallel. 2-4 is usually best for spinning disks, \
+                    NVMe to NVMe copies of many small files benefit from more"),
+        Arg::with_name("block-size")
+             .long("block-size")
+             .visible_alias("buffer-size")
+             .takes_value(true)
+             .value_name("SIZE")
+             .default_value(&defaults.block_size)
+             .help("read and write buffer size of every job, multiple of 4K. \
+                    bigger is faster for large files on fast disks, smaller makes progress smoother"),
+        Arg::with_name("retries")
+             .long("retries")
+             .takes_value(true)
+             .value_name("N")
+             .default_value("0")
+             .help("on transient i/o error (EIO, EAGAIN, timeout) copy file again up to N times, waiting longer every time, before asking what to do"),
+        Arg::with_name("on-error")
+             .long("on-error")
+             .takes_value(true)
+             .value_name("ask|skip|retry:N|abort")
+             .default_value(&defaults.on_error)
+             .validator(|v| copy::OnError::parse(&v).map(|_| ()).map_err(|err| err.to_string()))
+             .help("what to do when a file fails: ask, skip it, copy it again up to N times and then skip, or stop"),
+        Arg::with_name("atomic")
+             .long("atomic")
+             .help("write to temp file next to destination and rename it into place when complete, \
+                    so destination is never half written"),
+        Arg::with_name("resume")
+             .long("resume")
+             .help("continue files left partial by interrupted copy, skip the ones already copied. implies --update"),
+        Arg::with_name("limit-rate")
+             .long("limit-rate")
+             .takes_value(true)
+             .value_name("RATE")
+             .help("copy at most RATE bytes per second, like 500K or 10M"),
+        Arg::with_name("reflink")
+             .long("reflink")
+             .takes_value(true)
+             .value_name("WHEN")
+             .require_equals(true)
+             .possible_values(&["auto", "always", "never"])
+             .default_value("auto")
+             .help("auto: share data blocks on btrfs/xfs/apfs or copy inside kernel, read and write if neither works. \
+                    always: fail if blocks can't be shared. never: always read and write"),
+        Arg::with_name("sparse")
+             .long("sparse")
+             .takes_value(true)
+             .value_name("WHEN")
+             .require_equals(true)
+             .possible_values(&["auto", "always", "never"])
+             .default_value("auto")
+             .help("auto: holes of sparse source files stay holes. always: also make holes of zero blocks. \
+                    never: write holes as zeros"),
+        Arg::with_name("verify")
+             .long("verify")
+             .visible_alias("checksum")
+             .help("read back every copied file and compare its checksum with the one computed while reading source. \
+                    mismatch is an error, handled as --on-error says"),
+        Arg::with_name("checksum-algorithm")
+             .long("checksum-algorithm")
+             .takes_value(true)
+             .value_name("ALG")
+             .possible_values(&["sha256", "xxhash", "blake3"])
+             .help("hash for --verify and --skip-existing=checksum: blake3 (default), xxhash (fastest, not cryptographic) or sha256"),
+        Arg::with_name("preserve-mtime")
+             .long("preserve-mtime")
+             .help("keep modification time of source files. same as --preserve=timestamps"),
+        Arg::with_name("preserve")
+             .long("preserve")
+             .takes_value(true)
+             .value_name("ATTRS")
+             .use_delimiter(true)
+             .require_equals(true)
+             .possible_values(&["timestamps", "mode", "ownership", "xattrs", "acl"])
+             .help("keep attributes of source files and dirs, comma separated. acl implies mode, --no-preserve=mode is an error with it"),
+        Arg::with_name("no-preserve")
+             .long("no-preserve")
+             .takes_value(true)
+             .value_name("ATTRS")
+             .use_delimiter(true)
+             .require_equals(true)
+             .possible_values(&["timestamps", "mode", "ownership", "xattrs", "acl"])
+             .help("don't keep these attributes, even with -a or -p"),
+        Arg::with_name("archive")
+             .short("a")
+             .long("archive")
+             .help("keep everything: same as --preserve=mode,timestamps,ownership,xattrs,acl, symlinks are copied as links"),
+        Arg::with_name("no-dereference")
+             .short("P")
+             .long("no-dereference")
+             .visible_alias("no-follow-symlinks")
+             .help("copy all symlinks as symlinks with the same target, sources given in command line too"),
+        Arg::with_name("dereference")
+             .short("L")
+             .long("dereference")
+             .visible_alias("follow-symlinks")
+             .conflicts_with("no-dereference")
+             .help("follow symlinks, copy files and dirs they point to"),
+        Arg::with_name("follow-symlinks-once")
+             .long("follow-symlinks-once")
+             .conflicts_with_all(&["dereference", "no-dereference"])
+             .help("follow symlinks given in command line, copy the ones inside dirs as symlinks. this is the default"),
+        Arg::with_name("one-file-system")
+             .short("x")
+             .long("one-file-system")
+             .help("don't walk into dirs on other filesystems than source, like /proc under /. \
+                    mount points are created empty, like with cp -x and rsync -x. they are listed with -v"),
+        Arg::with_name("special-files")
+             .long("special-files")
+             .help("recreate named pipes, and device nodes when running as root. without it they are skipped and counted. \
+                    sockets are always skipped"),
+        Arg::with_name("hard-links")
+             .short("H")
+             .long("hard-links")
+             .help("copy file with several links in source once, link the rest to the copy, like rsync -H"),
+        Arg::with_name("verbose")
+             .short("v")
+             .long("verbose")
+             .help("print what is preserved before start"),
+        Arg::with_name("preserve-mode")
+             .short("p")
+             .long("preserve-mode")
+             .help("keep permission bits of source files exactly. implies --preserve-mtime"),
+        Arg::with_name("no-clobber")
+             .short("n")
+             .long("no-clobber")
+             .help("don't overwrite existing files"),
+        Arg::with_name("interactive")
+             .short("i")
+             .long("interactive")
+             .help("ask what to do with existing files: overwrite, keep both or skip"),
+        Arg::with_name("force")
+             .short("f")
+             .long("force")
+             .conflicts_with("no-clobber")
+             .help("destination file that can't be opened for writing is removed and created again, \
+                    or made writable if its dir is read-only"),
+        Arg::with_name("rename-conflicts")
+             .long("rename-conflicts")
+             .conflicts_with_all(&["no-clobber", "interactive", "update"])
+             .help("don't overwrite existing files, copy to the first free name (1).ext, name (2).ext instead. \
+                    -v lists new names. not for sync"),
+        Arg::with_name("update")
+             .short("u")
+             .long("update")
+             .help("copy only when source is newer than destination or destination is missing. \
+                    symlinks are compared by their own mtime. destination dir in place of a file is an error"),
+        Arg::with_name("skip-existing")
+             .long("skip-existing")
+             .takes_value(true)
+             .value_name("MODE")
+             .min_values(0)
+             .require_equals(true)
+             .possible_values(&["size", "size+mtime", "checksum"])
+             .help("don't copy files the same as existing destination files. size: same size. \
+                    size+mtime (default): same size and modification time. checksum: same size and contents, \
+                    both files are read"),
+        Arg::with_name("size-only")
+             .long("size-only")
+             .requires("update")
+             .help("with --update: skip files of the same size, don't look at mtime"),
+        Arg::with_name("quiet")
+             .short("q")
+             .long("quiet")
+             .help("only summary at the end, for scripts. errors are printed to stderr, \
+                   exit code is not 0 if some files failed. same as --progress none without details of summary"),
+        Arg::with_name("json-progress")
+             .long("json-progress")
+             .help("same as --progress json"),
+        Arg::with_name("progress")
+             .long("progress")
+             .takes_value(true)
+             .value_name("MODE")
+             .possible_values(&["auto", "bar", "spinner", "plain", "none", "json"])
+             .default_value(&defaults.progress)
+             .help("bar: progress bars, spinner: only current file, plain: line of totals every 5 seconds, \
+                    none: only summary at the end. auto: bars if stdout is a terminal, plain otherwise. \
+                    json: json object per event on stdout, summary as the last one"),
+        Arg::with_name("log-file")
+             .long("log-file")
+             .takes_value(true)
+             .value_name("PATH")
+             .help("append json object per line for every copied, failed or deleted file to PATH"),
+        Arg::with_name("log-level")
+             .long("log-level")
+             .takes_value(true)
+             .possible_values(&["info", "warn", "error"])
+             .requires("log-file")
+             .help("info: every file, warn: warnings and errors, error: only errors"),
+        Arg::with_name("exclude")
+             .long("exclude")
+             .takes_value(true)
+             .value_name("PATTERN")
+             .multiple(true)
+             .number_of_values(1)
+             .help("skip files and dirs matching glob pattern, like *.o or node_modules/**. \
+                    matched against path relative to source and against file name"),
+        Arg::with_name("gitignore")
+             .long("gitignore")
+             .help("skip files ignored by git: .gitignore and .ignore files, .git/info/exclude, global excludes file, \
+                    and .git itself. --include wins over them"),
+        Arg::with_name("strict")
+             .long("strict")
+             .help("source in command line with no files to copy is an error: empty dir, or everything in it excluded"),
+        Arg::with_name("no-hidden")
+             .long("no-hidden")
+             .help("skip files and dirs whose name starts with a dot, and hidden ones on windows. \
+                    sources given in command line are copied. -v tells how many were skipped"),
+        Arg::with_name("exclude-from")
+             .long("exclude-from")
+             .takes_value(true)
+             .value_name("FILE")
+             .multiple(true)
+             .number_of_values(1)
+             .help("read exclude patterns from file, one per line, - for stdin. # comments and blank lines are skipped"),
+        Arg::with_name("include")
+             .long("include")
+             .takes_value(true)
+             .value_name("PATTERN")
+             .multiple(true)
+             .number_of_values(1)
+             .help("copy files matching pattern even if later --exclude matches them. \
+                    rules are checked in command line order, first match wins. trailing / matches dirs only"),
+        Arg::with_name("include-from")
+             .long("include-from")
+             .takes_value(true)
+             .value_name("FILE")
+             .multiple(true)
+             .number_of_values(1)
+             .help("read include patterns from file, one per line, - for stdin"),
+        Arg::with_name("files-from")
+             .long("files-from")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("copy paths listed in FILE, one per line, - for stdin. only destination is given in command line"),
+        Arg::with_name("from0")
+             .short("0")
+             .long("from0")
+             .requires("files-from")
+             .help("--files-from paths end with NUL, not newline, like find -print0 writes them"),
+        Arg::with_name("source-root")
+             .long("source-root")
+             .visible_alias("base-dir")
+             .takes_value(true)
+             .value_name("DIR")
+             .requires("files-from")
+             .help("resolve --files-from paths relative to DIR instead of current dir"),
+        Arg::with_name("scan-first")
+             .long("scan-first")
+             .help("find all source files before copying, so totals and ETA are right from start"),
+        Arg::with_name("min-size")
+             .long("min-size")
+             .takes_value(true)
+             .value_name("SIZE")
+             .validator(|v| util::parse_size(&v).map(|_| ()).map_err(|err| err.to_string()))
+             .help("skip files smaller than SIZE, like 512K, 10M, 1.5G or 10MB for powers of 1000"),
+        Arg::with_name("max-size")
+             .long("max-size")
+             .takes_value(true)
+             .value_name("SIZE")
+             .validator(|v| util::parse_size(&v).map(|_| ()).map_err(|err| err.to_string()))
+             .help("skip files larger than SIZE, like 1.5G"),
+        Arg::with_name("newer-than")
+             .long("newer-than")
+             .takes_value(true)
+             .value_name("TIME")
+             .validator(|v| util::parse_time(&v).map(|_| ()).map_err(|err| err.to_string()))
+             .help("skip files modified before TIME: local date like 2024-01-31 or \"2024-01-31 12:30\", \
+                    or age like 30m, 12h, 7d, 2w"),
+        Arg::with_name("older-than")
+             .long("older-than")
+             .takes_value(true)
+             .value_name("TIME")
+             .validator(|v| util::parse_time(&v).map(|_| ()).map_err(|err| err.to_string()))
+             .help("skip files modified at TIME or later, same format as --newer-than"),
+        Arg::with_name("max-depth")
+             .long("max-depth")
+             .takes_value(true)
+             .value_name("N")
+             .help("copy at most N levels deep inside source dirs, like find. 1: only files and dirs right in them, \
+                    0: source dirs themselves, empty"),
+        Arg::with_name("min-depth")
+             .long("min-depth")
+             .takes_value(true)
+             .value_name("N")
+             .help("copy files at least N levels deep inside source dirs. 2: skip files right in them"),
+    ]
+}
 
-fn main() -> Result<(), Box<error::Error>> {
+fn main() -> Result<(), Box<std::error::Error>> {
+    // broken file is reported when an operation runs, config --edit must still work
+    let loaded = config::Config::load();
+    let defaults = loaded.as_ref().map(|config| config.defaults()).unwrap_or_else(|_| config::Config::default().defaults());
+    let mut args: Vec<String> = std::env::args().collect();
+    if let (Ok(config), Some(command)) = (loaded.as_ref(), args.get(1)) {
+        if ["cp", "mv", "sync"].contains(&command.as_str()) {
+            // before "--", after it all is sources
+            let end = args.iter().position(|arg| arg == "--").unwrap_or_else(|| args.len());
+            let excludes = config.exclude_args();
+            args.splice(end..end, excludes);
+        }
+    }
     let matches = App::new("ppcp")
         .version("0.0.1")
         .author("Nikita Bilous <nikita@bilous.me>")
         .about("Copy files in console with progress bar")
-        .arg(Arg::with_name("source")
-             .index(1)
-             .required(true)
-             .help("source path")
-             .multiple(true)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("cp")
+             .about("copy files and directories")
+             .args(&operation_args(&defaults))
+             .arg(Arg::with_name("dry-run")
+                  .long("dry-run")
+                  .help("walk sources and report what would be copied, don't write anything")
+             )
         )
-        .arg(Arg::with_name("dest")
-             .required(true)
-             .help("destination path")
-             .multiple(false)
+        .subcommand(SubCommand::with_name("mv")
+             .about("move files and directories. rename if possible, copy and remove source otherwise")
+             .args(&operation_args(&defaults))
+        )
+        .subcommand(SubCommand::with_name("sync")
+             .about("copy new and updated files. with --delete, then delete files of destination which are not in source")
+             .args(&operation_args(&defaults))
+             .arg(Arg::with_name("delete")
+                  .long("delete")
+                  .help("delete files of destination which are not in source, after everything is copied")
+             )
+             .arg(Arg::with_name("delete-delay")
+                  .long("delete-delay")
+                  .takes_value(true)
+                  .value_name("SECS")
+                  .requires("delete")
+                  .help("delete only files missing from source for at least SECS, counted from first sync that found them missing")
+             )
+        )
+        .subcommand(SubCommand::with_name("config")
+             .about("edit or show ~/.config/ppcp/config.toml: defaults of cp, mv and sync options")
+             .arg(Arg::with_name("edit")
+                  .long("edit")
+                  .required_unless("dump")
+                  .help("open config file in $VISUAL or $EDITOR, it's created if missing")
+             )
+             .arg(Arg::with_name("dump")
+                  .long("dump")
+                  .conflicts_with("edit")
+                  .help("print effective configuration: config file with built-in defaults for the rest")
+             )
         )
-        .get_matches();
+        .get_matches_from(args);
 
-    let mut app = app::App::new();
-    app.run(&matches)?;
+    if let Some(matches) = matches.subcommand_matches("config") {
+        return Ok(config::run(matches, loaded)?);
+    }
+    loaded?;
+    let mut app = app::App::new(&matches);
+    let summary = app.run(&matches)?;
+    // nonzero exit status for scripts
+    if summary.errors > 0 {
+        Err(error::PpcpError::Errors(summary.errors))?
+    }
     Ok(())
 }
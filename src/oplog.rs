@@ -0,0 +1,78 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use crate::error::{PpcpError, Result};
+
+/// --log-level: events of this level and above are written
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// "info", "warn" or "error"
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "info" => Ok(LogLevel::Info),
+            "warn" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            _ => Err(PpcpError::InvalidArgument { name: "log-level", value: s.to_string() }),
+        }
+    }
+}
+
+/// --log-file: json object per line for every file operation.
+/// not buffered: every line is on disk before the next event, Ctrl-C loses nothing
+pub struct OpLog {
+    file: File,
+    level: LogLevel,
+}
+
+impl OpLog {
+    /// append mode: several ppcp processes can write to the same log, lines don't mix
+    pub fn open(path: &Path, level: LogLevel) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(OpLog { file, level })
+    }
+
+    /// entry is json object with fields of the event, "ts" and "event" are added to it
+    pub fn write(&mut self, level: LogLevel, event: &str, line: Value) {
+        if level < self.level {
+            return;
+        }
+        self.record(event, line);
+    }
+
+    /// written at any --log-level, like the command line and summary around file events
+    pub fn record(&mut self, event: &str, mut line: Value) {
+        line["ts"] = json!(timestamp(SystemTime::now()));
+        line["event"] = json!(event);
+        // one write per line, O_APPEND makes it atomic. log is not worth stopping copy for
+        self.file.write_all(format!("{}\n", line).as_bytes()).unwrap_or(());
+    }
+}
+
+/// "2020-01-31T12:34:56.789Z", UTC
+fn timestamp(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // days to civil date, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, d.subsec_millis())
+}
@@ -0,0 +1,340 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use indicatif::*;
+
+use ppcp::avgspeed::EmaSpeed;
+use ppcp::events::{OperationStatus, StatsChange, WorkerEvent};
+use ppcp::stats::{OperationStats, Summary, fmt_speed};
+
+/// bars are not redrawn more often, and speed is not sampled more often
+pub const REDRAW_INTERVAL: Duration = Duration::from_millis(97);
+/// weight of speed since last redraw in ETA of current file. slow start of a file is forgotten in a few seconds
+const ETA_ALPHA: f64 = 0.1;
+/// --progress plain prints a line this often, logs should not grow too fast
+pub const PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// live output of App. stats are already updated with the event
+pub trait ProgressRenderer {
+    fn on_event(&mut self, event: &WorkerEvent, stats: &mut OperationStats);
+    /// operation is done, print summary below what was drawn
+    fn finish(&self, summary: &Summary);
+}
+
+/// --progress mode. --json-progress is --progress json
+pub fn renderer(mode: &str, json: bool, sync: bool) -> Box<dyn ProgressRenderer> {
+    if json {
+        return Box::new(JsonLines);
+    }
+    match mode {
+        "json" => Box::new(JsonLines),
+        "bar" => Box::new(Bars::new(sync)),
+        "spinner" => Box::new(Spinner::new()),
+        "none" => Box::new(Quiet),
+        "plain" => Box::new(Plain::new()),
+        // auto: escape codes garble logs and pipes
+        _ if atty::is(atty::Stream::Stdout) => Box::new(Bars::new(sync)),
+        _ => Box::new(Plain::new()),
+    }
+}
+
+/// --progress bar: current file, files, bytes and, for sync, deleted files
+pub struct Bars {
+    pb_curr: ProgressBar,
+    pb_files: ProgressBar,
+    pb_bytes: ProgressBar,
+    pb_name: ProgressBar,
+    /// sync only: files deleted from destination
+    pb_delete: ProgressBar,
+    last_update: Instant,
+    /// speed for ETA of current file, and its bytes done at last redraw
+    eta_speed: EmaSpeed,
+    eta_done: u64,
+    /// set when bars are drawn for the last time, see wait_bars
+    pb_done: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Bars {
+    pub fn new(sync: bool) -> Self {
+        let pb_name = ProgressBar::with_draw_target(10, ProgressDrawTarget::stdout_nohz());
+        // \u{00A0} (nbsp) to make indicatif draw lines as wide as possible
+        // otherwise it leaves leftovers from prev lines at the end of lines
+        pb_name.set_style(ProgressStyle::default_spinner()
+            .template("{spinner} {wide_msg} \u{00A0}")
+        );
+        let pb_curr = ProgressBar::new(10);
+        pb_curr.set_style(ProgressStyle::default_bar()
+            .template("current {bar:40.} {bytes:>8}/{total_bytes:<8} {elapsed:>5} {wide_msg} \u{00A0}")
+        );
+        let pb_files = ProgressBar::with_draw_target(10, ProgressDrawTarget::stdout_nohz());
+        pb_files.set_style(ProgressStyle::default_bar()
+            .template("files   {bar:40} {pos:>8}/{len:<8} {wide_msg} \u{00A0}")
+        );
+        let pb_bytes = ProgressBar::with_draw_target(10, ProgressDrawTarget::stdout_nohz());
+        // no ETA while total keeps growing, see WalkDone
+        pb_bytes.set_style(ProgressStyle::default_bar()
+            .template("bytes   {bar:40} {bytes:>8}/{total_bytes:<8} {elapsed:>5} ETA - {wide_msg} \u{00A0}")
+            // .progress_chars("=> ")
+        );
+        let pb_delete = if sync { ProgressBar::new(0) } else { ProgressBar::hidden() };
+        pb_delete.set_style(ProgressStyle::default_spinner()
+            .template("deleted {pos:>8} {wide_msg} \u{00A0}")
+        );
+        let multi_pb = MultiProgress::new();
+        let pb_name = multi_pb.add(pb_name);
+        let pb_curr = multi_pb.add(pb_curr);
+        let pb_files = multi_pb.add(pb_files);
+        let pb_bytes = multi_pb.add(pb_bytes);
+        let pb_delete = if sync { multi_pb.add(pb_delete) } else { pb_delete };
+        multi_pb.set_move_cursor(true);
+        // MultiProgress::join blocks drawing bars until all of them are finished,
+        // it needs a thread of its own while the main one handles worker events
+        let pb_done = Arc::new((Mutex::new(false), Condvar::new()));
+        let pb_done2 = pb_done.clone();
+        thread::spawn(move || {
+            // signaled on error too, main thread must not wait forever
+            multi_pb.join().unwrap_or(());
+            let (ref done, ref cvar) = *pb_done2;
+            *done.lock().unwrap() = true;
+            cvar.notify_one();
+        });
+        Bars {
+            pb_curr,
+            pb_files,
+            pb_bytes,
+            pb_name,
+            pb_delete,
+            last_update: Instant::now(),
+            eta_speed: EmaSpeed::new(ETA_ALPHA),
+            eta_done: 0,
+            pb_done,
+        }
+    }
+
+    /// summary is printed below bars, not in the middle of their last redraw.
+    /// join thread may be done already, if bars were finished before the last worker event
+    fn wait_bars(&self) {
+        let (ref done, ref cvar) = *self.pb_done;
+        let mut done = done.lock().unwrap();
+        while !*done {
+            done = cvar.wait(done).unwrap();
+        }
+    }
+
+    fn update(&mut self, stats: &mut OperationStats) {
+        let elapsed = Instant::now().duration_since(self.last_update);
+        if elapsed < REDRAW_INTERVAL {
+            return
+        }
+        self.last_update = Instant::now();
+        self.pb_name.tick(); // spin the spinner
+        if stats.current_path.changed() {
+            self.pb_name.set_message(&format!("{}", stats.current_path.display()));
+            self.pb_curr.set_length(*stats.current_total as u64);
+            stats.current_start = Instant::now(); // This is inaccurate. Init current_start in copy worker and send instant with path?
+            self.pb_curr.reset_elapsed();
+            stats.current_speed.reset();
+            self.eta_speed.reset();
+            self.eta_done = 0;
+        }
+        stats.current_speed.add(stats.current_done);
+        if stats.current_done < self.eta_done {
+            // file is copied again after error
+            self.eta_speed.reset();
+        }
+        else {
+            self.eta_speed.update(stats.current_done - self.eta_done, elapsed);
+        }
+        self.eta_done = stats.current_done;
+        self.pb_curr.set_draw_delta(0);
+        self.pb_curr.set_position(stats.current_done as u64);
+        let phase = match stats.retrying {
+            Some((attempt, retries)) => format!("retry {}/{} ", attempt, retries),
+            None if stats.verifying => "verify ".to_string(),
+            None => "".to_string(),
+        };
+        let eta = match self.eta_speed.eta(stats.current_total.saturating_sub(stats.current_done)) {
+            Some(eta) => format!("{}", HumanDuration(eta)),
+            None => "-".to_string(),
+        };
+        self.pb_curr.set_message(&format!("ETA {} {}{}", eta, phase, fmt_speed(stats.current_speed.get())));
+
+        if stats.files_total.changed() {
+            self.pb_files.set_length(*stats.files_total as u64);
+        }
+        self.pb_files.set_position(u64::from(stats.files_done));
+
+        if stats.bytes_total.changed() {
+            self.pb_bytes.set_length(*stats.bytes_total as u64);
+        }
+        self.pb_bytes.set_position(stats.bytes_done as u64);
+        if stats.walk_done {
+            self.pb_bytes.set_message(&fmt_speed(stats.avg_speed.get()));
+        }
+        else {
+            self.pb_bytes.set_message(&format!("{}, still finding files", fmt_speed(stats.avg_speed.get())));
+        }
+
+        if stats.deleting.changed() {
+            self.pb_delete.set_position(stats.files_deleted);
+            self.pb_delete.set_message(&format!("{}", stats.deleting.display()));
+        }
+    }
+}
+
+impl ProgressRenderer for Bars {
+    fn on_event(&mut self, event: &WorkerEvent, stats: &mut OperationStats) {
+        match *event {
+            WorkerEvent::Scanning(files, bytes) => {
+                self.pb_name.set_message(&format!("scanning: {} files, {}", files, HumanBytes(bytes)));
+                self.pb_name.tick();
+            }
+            WorkerEvent::WalkDone => {
+                self.pb_bytes.set_style(ProgressStyle::default_bar()
+                    .template("bytes   {bar:40} {bytes:>8}/{total_bytes:<8} {elapsed:>5} ETA {eta} {wide_msg} \u{00A0}")
+                );
+            }
+            _ => {}
+        }
+        self.update(stats);
+    }
+
+    fn finish(&self, summary: &Summary) {
+        self.pb_curr.finish();
+        self.pb_files.finish();
+        self.pb_bytes.finish();
+        self.pb_name.finish();
+        self.pb_delete.finish();
+        self.wait_bars();
+        println!("{}", summary);
+    }
+}
+
+/// --progress spinner: one line with the current file, no escape codes to move cursor
+pub struct Spinner {
+    pb: ProgressBar,
+    last_update: Instant,
+}
+
+impl Spinner {
+    pub fn new() -> Self {
+        let pb = ProgressBar::with_draw_target(10, ProgressDrawTarget::stdout_nohz());
+        pb.set_style(ProgressStyle::default_spinner()
+            .template("{spinner} {wide_msg} \u{00A0}")
+        );
+        Spinner { pb, last_update: Instant::now() }
+    }
+}
+
+impl ProgressRenderer for Spinner {
+    fn on_event(&mut self, event: &WorkerEvent, stats: &mut OperationStats) {
+        if let WorkerEvent::Scanning(files, bytes) = *event {
+            self.pb.set_message(&format!("scanning: {} files, {}", files, HumanBytes(bytes)));
+        }
+        if Instant::now().duration_since(self.last_update) < REDRAW_INTERVAL {
+            return
+        }
+        self.last_update = Instant::now();
+        if stats.current_path.changed() {
+            self.pb.set_message(&format!("{}", stats.current_path.display()));
+        }
+        self.pb.tick();
+    }
+
+    fn finish(&self, summary: &Summary) {
+        self.pb.finish_and_clear();
+        println!("{}", summary);
+    }
+}
+
+/// --progress plain: a line of totals every few seconds, no escape codes. for cron, CI and nohup logs
+pub struct Plain {
+    last_line: Instant,
+}
+
+impl Plain {
+    pub fn new() -> Self {
+        Plain { last_line: Instant::now() }
+    }
+}
+
+impl ProgressRenderer for Plain {
+    fn on_event(&mut self, _event: &WorkerEvent, stats: &mut OperationStats) {
+        if Instant::now().duration_since(self.last_line) < PLAIN_INTERVAL {
+            return
+        }
+        self.last_line = Instant::now();
+        println!("[{}/{} files] {} / {}  {}  current: {}",
+                 stats.files_done, *stats.files_total, HumanBytes(stats.bytes_done), HumanBytes(*stats.bytes_total),
+                 fmt_speed(stats.avg_speed.get()), stats.current_path.display());
+    }
+
+    fn finish(&self, summary: &Summary) {
+        println!("{}", summary);
+    }
+}
+
+/// --progress none: nothing until summary
+pub struct Quiet;
+
+impl ProgressRenderer for Quiet {
+    fn on_event(&mut self, _event: &WorkerEvent, _stats: &mut OperationStats) {}
+
+    fn finish(&self, summary: &Summary) {
+        println!("{}", summary);
+    }
+}
+
+/// --progress json: one line for every event, for scripts and GUI frontends.
+/// event name and message, then totals after it
+pub struct JsonLines;
+
+impl ProgressRenderer for JsonLines {
+    fn on_event(&mut self, event: &WorkerEvent, stats: &mut OperationStats) {
+        let mut line = json!({
+            "event": event.name(),
+            "message": event.to_string(),
+            "files_done": stats.files_done,
+            "files_total": *stats.files_total,
+            "bytes_done": stats.bytes_done,
+            "bytes_total": *stats.bytes_total,
+            "files_failed": stats.failed.len(),
+            "files_deleted": stats.files_deleted,
+            "files_linked": stats.files_linked,
+            "walk_done": stats.walk_done,
+            "current_file": stats.current_path.display().to_string(),
+            "current_done": stats.current_done,
+            "current_total": *stats.current_total,
+            "speed_bps": stats.avg_speed.get(),
+            "elapsed_secs": stats.started.elapsed().as_secs_f64(),
+        });
+        match *event {
+            // consumer may show or log errors, it gets them as fields
+            WorkerEvent::Status(OperationStatus::Error(ref ctx)) | WorkerEvent::Status(OperationStatus::MetadataError(ref ctx)) => {
+                line["src"] = json!(ctx.src.display().to_string());
+                line["dst"] = json!(ctx.dest.as_ref().map(|dest| dest.display().to_string()));
+                line["error"] = json!(format!("{:?} ({})", ctx.err.kind(), ctx.err));
+            }
+            WorkerEvent::Stat(StatsChange::FileFailed(_, _, ref p, ref reason)) | WorkerEvent::Stat(StatsChange::DirFailed(ref p, ref reason)) => {
+                line["src"] = json!(p.display().to_string());
+                line["error"] = json!(reason);
+            }
+            WorkerEvent::Stat(StatsChange::FileSkipped(_, _, ref p)) => {
+                line["src"] = json!(p.display().to_string());
+            }
+            _ => {}
+        }
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        // consumer must see progress immediately, not when buffer is full
+        writeln!(out, "{}", line).and_then(|_| out.flush()).expect("stdout");
+    }
+
+    fn finish(&self, summary: &Summary) {
+        let mut line = serde_json::to_value(summary).expect("json");
+        line["event"] = json!("summary");
+        println!("{}", line);
+    }
+}
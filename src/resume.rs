@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+
+/// saved to destination root when copy is interrupted, read with --resume
+pub const STATE_FILE: &str = ".ppcp_state";
+
+/// partially written file: source, bytes written, source size
+#[derive(Clone, Debug)]
+pub struct Partial {
+    pub src: PathBuf,
+    pub written: u64,
+    pub size: u64,
+    /// --atomic: data is written here, renamed to destination when done
+    pub tmp: Option<PathBuf>,
+}
+
+/// files being copied right now, by destination path
+pub type ResumeState = Arc<Mutex<HashMap<PathBuf, Partial>>>;
+
+/// partial files from previous run. missing or broken state file means nothing to resume
+pub fn load(dest_dir: &Path) -> HashMap<PathBuf, Partial> {
+    let mut partial = HashMap::new();
+    let state: Value = match File::open(dest_dir.join(STATE_FILE)).map(BufReader::new) {
+        Ok(f) => match serde_json::from_reader(f) {
+            Ok(state) => state,
+            Err(_) => return partial,
+        },
+        Err(_) => return partial,
+    };
+    for file in state["files"].as_array().into_iter().flatten() {
+        if let (Some(src), Some(dst), Some(written), Some(size)) =
+            (file["src_path"].as_str(), file["dst_path"].as_str(), file["bytes_written"].as_u64(), file["src_size"].as_u64())
+        {
+            let tmp = file["tmp_path"].as_str().map(PathBuf::from);
+            partial.insert(PathBuf::from(dst), Partial { src: PathBuf::from(src), written, size, tmp });
+        }
+    }
+    partial
+}
+
+pub fn save(dest_dir: &Path, partial: &HashMap<PathBuf, Partial>) -> io::Result<()> {
+    let files: Vec<Value> = partial.iter().map(|(dst, p)| json!({
+        "src_path": p.src.display().to_string(),
+        "dst_path": dst.display().to_string(),
+        "bytes_written": p.written,
+        "src_size": p.size,
+        "tmp_path": p.tmp.as_ref().map(|tmp| tmp.display().to_string()),
+    })).collect();
+    let mut f = BufWriter::new(File::create(dest_dir.join(STATE_FILE))?);
+    serde_json::to_writer(&mut f, &json!({ "files": files }))
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    f.flush()
+}
+
+/// copy is interrupted. temp files of --atomic are kept for --resume, removed otherwise
+pub fn interrupted(dest_dir: &Path, partial: &HashMap<PathBuf, Partial>, keep_tmp: bool) {
+    let mut partial = partial.clone();
+    if !keep_tmp {
+        partial.retain(|_, part| match part.tmp {
+            Some(ref tmp) => {
+                fs::remove_file(tmp).unwrap_or(());
+                false
+            }
+            None => true,
+        });
+    }
+    save(dest_dir, &partial).unwrap_or(());
+}
+
+/// copy is complete, nothing to resume
+pub fn remove(dest_dir: &Path) {
+    fs::remove_file(dest_dir.join(STATE_FILE)).unwrap_or(());
+}
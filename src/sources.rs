@@ -0,0 +1,101 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::PathBuf;
+
+use crate::error::{PpcpError, Result};
+
+/// sources of operation. every path existed when the list was made
+#[derive(Clone, Debug, Default)]
+pub struct SourceList(Vec<PathBuf>);
+
+impl SourceList {
+    /// error for the first path that does not exist. broken symlink exists, it's copied as link
+    pub fn new(paths: Vec<PathBuf>) -> Result<Self> {
+        if let Some(missing) = paths.iter().find(|p| fs::symlink_metadata(p).is_err()) {
+            Err(PpcpError::SourceNotFound(missing.clone()))?
+        }
+        Ok(SourceList(paths))
+    }
+
+    /// paths matching glob pattern, like photos/*.jpg. error if none does
+    pub fn with_glob(pattern: &str) -> Result<Self> {
+        let paths = glob::glob(pattern)
+            .map_err(|err| PpcpError::InvalidArgument { name: "source", value: format!("{}, {}", pattern, err) })?;
+        let mut list = Vec::new();
+        for path in paths {
+            // dir that can't be read while matching
+            list.push(path.map_err(|err| err.into_error())?);
+        }
+        if list.is_empty() {
+            Err(PpcpError::SourceNotFound(PathBuf::from(pattern)))?
+        }
+        Ok(SourceList(list))
+    }
+
+    /// sources as given in command line: paths, or patterns the shell left as is,
+    /// like '*.log' in quotes or a pattern that matched nothing there
+    pub fn from_args(args: Vec<PathBuf>) -> Result<Self> {
+        let mut list = Vec::new();
+        for arg in args {
+            if fs::symlink_metadata(&arg).is_ok() {
+                list.push(arg);
+                continue;
+            }
+            match arg.to_str() {
+                Some(pattern) if pattern.contains(|c| c == '*' || c == '?' || c == '[') => {
+                    list.extend(SourceList::with_glob(pattern)?.0);
+                }
+                _ => Err(PpcpError::SourceNotFound(arg))?,
+            }
+        }
+        Ok(SourceList(list))
+    }
+}
+
+impl Deref for SourceList {
+    type Target = [PathBuf];
+
+    fn deref(&self) -> &[PathBuf] {
+        &self.0
+    }
+}
+
+impl IntoIterator for SourceList {
+    type Item = PathBuf;
+    type IntoIter = std::vec::IntoIter<PathBuf>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn not_found(result: Result<SourceList>) -> PathBuf {
+        match result {
+            Err(PpcpError::SourceNotFound(p)) => p,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_path_is_source_not_found() {
+        // tests run in crate root
+        let paths = vec![PathBuf::from("src"), PathBuf::from("does/not/exist")];
+        assert_eq!(not_found(SourceList::new(paths)), PathBuf::from("does/not/exist"));
+    }
+
+    #[test]
+    fn path_through_file_is_source_not_found() {
+        assert_eq!(not_found(SourceList::new(vec![PathBuf::from("Cargo.toml/x")])), PathBuf::from("Cargo.toml/x"));
+    }
+
+    #[test]
+    fn pattern_matching_nothing_is_source_not_found() {
+        assert_eq!(not_found(SourceList::from_args(vec![PathBuf::from("src/*.nothing")])), PathBuf::from("src/*.nothing"));
+        let list = SourceList::from_args(vec![PathBuf::from("src/lib.r?")]).unwrap();
+        assert_eq!(&*list, &[PathBuf::from("src/lib.rs")][..]);
+    }
+}
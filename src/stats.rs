@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use indicatif::{HumanBytes, HumanDuration};
+
+use crate::avgspeed::{get_speed, AvgSpeed, WindowSpeed};
+use crate::copy::Operation;
+use crate::events::{OperationStatus, SkipReason, SpecialKind, StatsChange, WorkerEvent};
+use crate::trackchange::TrackChange;
+
+/// seconds of current file speed: how fast it goes right now, not since start
+pub const FILE_SPEED_WINDOW: u64 = 2;
+
+/// "1.5MB/s"
+pub fn fmt_speed(bytes_per_sec: u64) -> String {
+    format!("{}/s", HumanBytes(bytes_per_sec))
+}
+
+/// progress of operation, built from WorkerEvents by frontend
+pub struct OperationStats {
+    pub files_done: u32,
+    pub bytes_done: u64,
+    pub files_total: TrackChange<u64>,
+    pub bytes_total: TrackChange<u64>,
+    pub current_total: TrackChange<u64>,
+    pub current_done: u64,
+    pub current_path: TrackChange<PathBuf>,
+    pub current_start: Instant,
+    /// speed of current file over last seconds, for its ETA
+    pub current_speed: WindowSpeed,
+    /// with --jobs several files are copied at once: path -> done, total.
+    /// the largest one is shown as current
+    pub in_flight: HashMap<PathBuf, (u64, u64)>,
+    pub files_renamed: u64,
+    pub bytes_renamed: u64,
+    /// dry run: destination files that would be overwritten
+    pub conflicts: Vec<PathBuf>,
+    /// --rename-conflicts: existing destination and the free name file was copied to
+    pub conflicts_renamed: Vec<(PathBuf, PathBuf)>,
+    pub bytes_verified: u64,
+    /// --verify: files with matching checksum
+    pub files_verified: u64,
+    pub verify_errors: u64,
+    /// current file is being read back
+    pub verifying: bool,
+    /// current file failed and is copied again: attempt, max attempts
+    pub retrying: Option<(u32, u32)>,
+    pub warnings: Vec<String>,
+    pub files_skipped: u64,
+    pub files_up_to_date: u64,
+    pub files_excluded: u64,
+    /// out of size or mtime range
+    pub files_filtered: u64,
+    /// --no-hidden: dot files and dirs not walked
+    pub hidden_skipped: u64,
+    /// --hard-links: linked to another copied file, not copied
+    pub files_linked: u64,
+    /// copied by reflink, sharing data blocks with source
+    pub files_cloned: u64,
+    /// dirs that did not exist in destination, empty ones included
+    pub dirs_created: u64,
+    /// --sparse: bytes left as holes instead of written
+    pub bytes_saved: u64,
+    /// recreated as links, counted in files copied too
+    pub symlinks_copied: u64,
+    /// --special-files: fifos and device nodes recreated, counted in files copied too
+    pub specials_copied: u64,
+    /// special files not copied, by kind
+    pub fifos_skipped: u64,
+    pub sockets_skipped: u64,
+    pub devices_skipped: u64,
+    /// copied over older destination files, counted in files copied too
+    pub files_updated: u64,
+    /// -x: mount points under sources, not walked into
+    pub mounts_skipped: Vec<PathBuf>,
+    /// --files-from: listed paths that don't exist
+    pub sources_missing: Vec<PathBuf>,
+    /// chown failed, not root
+    pub owner_not_preserved: u64,
+    pub xattrs_not_preserved: u64,
+    /// --preserve=acl to filesystem without acls
+    pub acl_not_preserved: u64,
+    /// sync: file being deleted from destination
+    pub deleting: TrackChange<PathBuf>,
+    pub files_deleted: u64,
+    /// source walk is complete, totals don't grow anymore
+    pub walk_done: bool,
+    /// files skipped after error, with the error
+    pub failed: Vec<(PathBuf, String)>,
+    pub started: Instant,
+    /// speed of all files, see sample_speed
+    pub avg_speed: AvgSpeed,
+    /// highest average speed seen while copying
+    pub peak_speed: u64,
+}
+
+impl Default for OperationStats {
+    fn default() -> Self {
+        OperationStats {
+            files_done: 0,
+            bytes_done: 0,
+            files_total: TrackChange::new(0),
+            bytes_total: TrackChange::new(0),
+            current_total: TrackChange::new(0),
+            current_done: 0,
+            current_path: TrackChange::new(PathBuf::new()),
+            current_start: Instant::now(),
+            current_speed: WindowSpeed::new(Duration::from_secs(FILE_SPEED_WINDOW)),
+            in_flight: HashMap::new(),
+            files_renamed: 0,
+            bytes_renamed: 0,
+            conflicts: Vec::new(),
+            conflicts_renamed: Vec::new(),
+            bytes_verified: 0,
+            files_verified: 0,
+            verify_errors: 0,
+            verifying: false,
+            retrying: None,
+            warnings: Vec::new(),
+            files_skipped: 0,
+            files_up_to_date: 0,
+            files_excluded: 0,
+            files_filtered: 0,
+            hidden_skipped: 0,
+            files_linked: 0,
+            files_cloned: 0,
+            dirs_created: 0,
+            bytes_saved: 0,
+            symlinks_copied: 0,
+            specials_copied: 0,
+            fifos_skipped: 0,
+            sockets_skipped: 0,
+            devices_skipped: 0,
+            files_updated: 0,
+            mounts_skipped: Vec::new(),
+            sources_missing: Vec::new(),
+            owner_not_preserved: 0,
+            xattrs_not_preserved: 0,
+            acl_not_preserved: 0,
+            deleting: TrackChange::new(PathBuf::new()),
+            files_deleted: 0,
+            walk_done: false,
+            failed: Vec::new(),
+            started: Instant::now(),
+            avg_speed: AvgSpeed::new(),
+            peak_speed: 0,
+        }
+    }
+}
+
+impl OperationStats {
+    /// count event in. every variant is matched: new events can't be ignored by accident
+    pub fn update(&mut self, event: &WorkerEvent) {
+        match *event {
+            WorkerEvent::Stat(ref change) => self.update_stat(change),
+            WorkerEvent::Status(OperationStatus::Warning(ref msg)) => {
+                self.warnings.push(msg.clone());
+            }
+            // retried error is no failure. file or dir that is skipped comes as FileFailed or DirFailed
+            WorkerEvent::Status(OperationStatus::Error(_)) | WorkerEvent::Status(OperationStatus::MetadataError(_)) => {}
+            // answered by frontend, nothing to count
+            WorkerEvent::Status(OperationStatus::Conflict(..)) => {}
+            WorkerEvent::VerifyError(_) => {
+                self.verify_errors += 1;
+            }
+            WorkerEvent::Deleted(ref p) => {
+                self.deleting.set(p.clone());
+                self.files_deleted += 1;
+            }
+            // totals come with BytesTotal
+            WorkerEvent::Scanning(..) => {}
+            WorkerEvent::WalkDone => {
+                self.walk_done = true;
+            }
+            WorkerEvent::Started { .. } | WorkerEvent::Finished { .. } => {}
+        }
+    }
+
+    fn update_stat(&mut self, change: &StatsChange) {
+        match *change {
+            StatsChange::FileDone(ref p) => {
+                self.files_done += 1;
+                // file that shrank while copied never had done >= total
+                self.in_flight.remove(p);
+            }
+            StatsChange::BytesTotal(n) => {
+                *self.bytes_total += n;
+                *self.files_total += 1;
+            }
+            StatsChange::Current(ref p, chunk, done, todo) => {
+                if done >= todo {
+                    self.in_flight.remove(p);
+                }
+                else {
+                    self.in_flight.insert(p.clone(), (done, todo));
+                }
+                // the largest file takes longest, it's the one worth watching
+                let (p, (done, todo)) = self.in_flight.iter()
+                    .max_by_key(|(_, (_, todo))| *todo)
+                    .map(|(p, progress)| (p.clone(), *progress))
+                    .unwrap_or_else(|| (p.clone(), (done, todo)));
+                self.current_path.set(p);
+                self.current_total.set(todo);
+                self.current_done = done;
+                self.bytes_done += chunk;
+                self.verifying = false;
+                self.retrying = None;
+            }
+            StatsChange::Verify(ref p, chunk, done, todo) => {
+                self.current_path.set(p.clone());
+                self.current_total.set(todo);
+                self.current_done = done;
+                self.bytes_verified += chunk;
+                self.verifying = true;
+            }
+            StatsChange::Verified => self.files_verified += 1,
+            StatsChange::FileSkipped(size, reason, _) => {
+                // done with the file, but its bytes are not copied
+                self.files_done += 1;
+                self.files_skipped += 1;
+                if reason == SkipReason::UpToDate {
+                    self.files_up_to_date += 1;
+                }
+                *self.bytes_total -= size;
+            }
+            StatsChange::Cloned => self.files_cloned += 1,
+            StatsChange::Updated => self.files_updated += 1,
+            StatsChange::SymlinkCopied => self.symlinks_copied += 1,
+            StatsChange::SpecialCopied => self.specials_copied += 1,
+            StatsChange::SpecialSkipped(kind, _) => match kind {
+                SpecialKind::Fifo => self.fifos_skipped += 1,
+                SpecialKind::Socket => self.sockets_skipped += 1,
+                SpecialKind::Device => self.devices_skipped += 1,
+            },
+            StatsChange::DirCreated => self.dirs_created += 1,
+            StatsChange::Sparse(bytes) => self.bytes_saved += bytes,
+            StatsChange::MountSkipped(ref path) => {
+                // same mount under several sources
+                if !self.mounts_skipped.contains(path) {
+                    self.mounts_skipped.push(path.clone());
+                }
+            }
+            StatsChange::SourceMissing(ref path) => {
+                // exit status is nonzero, like for other files not copied
+                self.sources_missing.push(path.clone());
+            }
+            StatsChange::HardLinked(size) => {
+                self.files_done += 1;
+                self.files_linked += 1;
+                *self.bytes_total -= size;
+            }
+            StatsChange::FileExcluded => self.files_excluded += 1,
+            StatsChange::FileFiltered => self.files_filtered += 1,
+            StatsChange::HiddenSkipped => self.hidden_skipped += 1,
+            StatsChange::OwnerNotPreserved => self.owner_not_preserved += 1,
+            StatsChange::XattrsNotPreserved => self.xattrs_not_preserved += 1,
+            StatsChange::AclNotPreserved => self.acl_not_preserved += 1,
+            StatsChange::Conflict(ref p) => self.conflicts.push(p.clone()),
+            StatsChange::ConflictRenamed(ref existing, ref free) => {
+                self.conflicts_renamed.push((existing.clone(), free.clone()));
+            }
+            StatsChange::Renamed(files, bytes) => {
+                *self.files_total += files;
+                *self.bytes_total += bytes;
+                self.files_done += files as u32;
+                self.bytes_done += bytes;
+                self.files_renamed += files;
+                self.bytes_renamed += bytes;
+            }
+            StatsChange::RenamedFile(size) => {
+                // done at once, like renames before the walk
+                self.files_done += 1;
+                self.bytes_done += size;
+                self.files_renamed += 1;
+                self.bytes_renamed += size;
+            }
+            StatsChange::Retry(done) => self.bytes_done -= done,
+            StatsChange::AutoRetry(done, attempt, retries) => {
+                self.bytes_done -= done;
+                self.retrying = Some((attempt, retries));
+            }
+            StatsChange::FileFailed(done, size, ref p, ref reason) => {
+                // skipped file is not counted in totals
+                self.bytes_done -= done;
+                *self.bytes_total -= size;
+                *self.files_total -= 1;
+                self.in_flight.remove(p);
+                self.failed.push((p.clone(), reason.clone()));
+            }
+            StatsChange::DirFailed(ref p, ref reason) => self.failed.push((p.clone(), reason.clone())),
+        }
+    }
+
+    /// files that were not copied in the end and sources not found
+    pub fn errors(&self) -> u64 {
+        (self.failed.len() + self.sources_missing.len()) as u64
+    }
+
+    /// add bytes done so far to avg_speed, every 100ms or so
+    pub fn sample_speed(&mut self) {
+        // renamed files were not copied, don't count them in speed
+        self.avg_speed.add(self.bytes_done - self.bytes_renamed);
+        self.peak_speed = self.peak_speed.max(self.avg_speed.get());
+    }
+
+    /// totals for the end of operation
+    pub fn summary(&self, operation: &dyn Operation) -> Summary {
+        let elapsed = self.started.elapsed();
+        Summary {
+            verb: operation.verb(),
+            dry_run: operation.dry_run(),
+            files_copied: *self.files_total - self.files_skipped - self.files_linked,
+            files_skipped: self.files_skipped,
+            files_cloned: self.files_cloned,
+            symlinks_copied: self.symlinks_copied,
+            files_updated: self.files_updated,
+            files_deleted: self.files_deleted,
+            files_failed: self.failed.len() as u64,
+            files_verified: self.files_verified,
+            conflicts_renamed: self.conflicts_renamed.len() as u64,
+            bytes_total: *self.bytes_total,
+            bytes_saved: self.bytes_saved,
+            elapsed_secs: elapsed.as_secs_f64(),
+            // renamed files were not copied, don't count them in speed
+            avg_speed: get_speed(*self.bytes_total - self.bytes_renamed, &elapsed),
+            peak_speed: self.peak_speed,
+            errors: self.errors(),
+        }
+    }
+}
+
+/// result of operation: printed at exit, json with --json-progress
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    /// "copied", "moved"
+    pub verb: &'static str,
+    pub dry_run: bool,
+    pub files_copied: u64,
+    pub files_skipped: u64,
+    /// part of files_copied done by reflink
+    pub files_cloned: u64,
+    /// of files_copied
+    pub symlinks_copied: u64,
+    /// of files_copied, replaced existing destination files
+    pub files_updated: u64,
+    pub files_deleted: u64,
+    pub files_failed: u64,
+    /// --verify: read back with matching checksum
+    pub files_verified: u64,
+    /// --rename-conflicts: copied to a new name, destination existed
+    pub conflicts_renamed: u64,
+    pub bytes_total: u64,
+    /// holes of sparse files, not written
+    pub bytes_saved: u64,
+    pub elapsed_secs: f64,
+    /// bytes per second
+    pub avg_speed: u64,
+    pub peak_speed: u64,
+    /// files that failed and sources not found. exit status is nonzero if there are any
+    pub errors: u64,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<8} {} files ({})", self.verb, self.files_copied, HumanBytes(self.bytes_total))?;
+        writeln!(f, "{:<8} {} files", "skipped", self.files_skipped)?;
+        write!(f, "{:<8} {} files", "failed", self.files_failed)?;
+        if self.bytes_saved > 0 {
+            write!(f, "\n{:<8} {} left as holes", "sparse", HumanBytes(self.bytes_saved))?;
+        }
+        if self.dry_run {
+            // time and speed of dry run mean nothing
+            return Ok(());
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<8} {}", "elapsed", HumanDuration(Duration::from_secs_f64(self.elapsed_secs)))?;
+        write!(f, "{:<8} {} average, {} peak", "speed", fmt_speed(self.avg_speed), fmt_speed(self.peak_speed))
+    }
+}
@@ -0,0 +1,32 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// limits throughput to rate bytes per second, bursts up to one second worth of bytes
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// take n bytes worth of tokens, sleep if there is not enough
+    pub fn consume(&mut self, n: u64) {
+        let now = Instant::now();
+        let ela = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + ela * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+        self.tokens -= n as f64;
+        if self.tokens < 0.0 {
+            // debt is paid by sleeping, tokens are refilled on next call
+            thread::sleep(Duration::from_secs_f64(-self.tokens / self.rate as f64));
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+
+/// utility to track changes of variable
+#[derive(Default, Clone)]
+pub struct TrackChange<T: PartialEq> {
+    val: T,
+    changed: bool,
+    /// value before first deref_mut since last check. it's changed only if it differs from this
+    prev: Option<T>,
+}
+
+impl<T: PartialEq> TrackChange<T> {
+    /// initial value is not a change
+    pub fn new(val: T) -> Self {
+        TrackChange { val, changed: false, prev: None }
+    }
+    /// was value changed since last call. resets the flag
+    pub fn changed(&mut self) -> bool {
+        let r = self.peek_changed();
+        self.reset();
+        r
+    }
+    /// was value changed, without resetting the flag
+    pub fn peek_changed(&self) -> bool {
+        self.changed || self.prev.as_ref().map_or(false, |prev| *prev != self.val)
+    }
+    /// forget the change, like it was seen
+    pub fn reset(&mut self) {
+        self.changed = false;
+        self.prev = None;
+    }
+    /// value itself, tracking is over
+    pub fn into_inner(self) -> T {
+        self.val
+    }
+    /// changed only if val differs from current
+    pub fn set(&mut self, val: T) {
+        if val == self.val {
+            return
+        }
+        self.changed = true;
+        self.prev = None;
+        self.val = val;
+    }
+}
+impl<T: PartialEq> From<T> for TrackChange<T> {
+    fn from(val: T) -> Self {
+        TrackChange::new(val)
+    }
+}
+/// `let x: u64 = tracked.into()`. coherence rules out From<TrackChange<T>> for T and Into<T>
+/// for any T, so it's there for each type that is tracked
+macro_rules! from_track_change {
+    ($($t:ty),*) => {$(
+        impl From<TrackChange<$t>> for $t {
+            fn from(tracked: TrackChange<$t>) -> $t {
+                tracked.into_inner()
+            }
+        }
+    )*}
+}
+from_track_change!(u64, PathBuf);
+impl<T: PartialEq> Deref for TrackChange<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.val
+    }
+}
+/// `*x += 0` is not a change: old value is kept and compared when change is checked
+impl<T: PartialEq + Clone> DerefMut for TrackChange<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        if !self.changed && self.prev.is_none() {
+            self.prev = Some(self.val.clone());
+        }
+        &mut self.val
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initial_value_is_not_change() {
+        let mut x = TrackChange::new(1);
+        assert!(!x.changed());
+        let mut y: TrackChange<u64> = 5.into();
+        assert!(!y.changed());
+        assert_eq!(*y, 5);
+    }
+
+    #[test]
+    fn into_value() {
+        let mut x: TrackChange<u64> = 5.into();
+        *x += 1;
+        let val: u64 = x.into();
+        assert_eq!(val, 6);
+        let p: PathBuf = TrackChange::new(PathBuf::from("a")).into();
+        assert_eq!(p, PathBuf::from("a"));
+    }
+
+    #[test]
+    fn set() {
+        let mut x = TrackChange::new(1);
+        x.set(1);
+        assert!(!x.changed());
+        x.set(2);
+        assert!(x.peek_changed());
+        // checked once
+        assert!(x.changed());
+        assert!(!x.changed());
+        assert_eq!(x.into_inner(), 2);
+    }
+
+    #[test]
+    fn deref_mut_change() {
+        let mut x = TrackChange::new(1);
+        *x += 1;
+        assert_eq!(*x, 2);
+        assert!(x.changed());
+        assert!(!x.changed());
+        *x += 1;
+        *x += 1;
+        assert!(x.changed());
+        assert_eq!(*x, 4);
+    }
+
+    #[test]
+    fn deref_mut_after_set() {
+        let mut x = TrackChange::new(String::from("a"));
+        x.set(String::from("b"));
+        x.push('c');
+        assert!(x.changed());
+        assert_eq!(*x, "bc");
+    }
+
+    #[test]
+    fn no_op_mutation_is_not_change() {
+        let mut x = TrackChange::new(1);
+        *x += 0;
+        assert!(!x.changed());
+        // changed and changed back before check
+        *x = 2;
+        *x = 1;
+        assert!(!x.changed());
+        let mut s = TrackChange::new(String::from("a"));
+        s.push('b');
+        s.pop();
+        assert!(!s.changed());
+    }
+
+    #[test]
+    fn no_op_after_seen_change() {
+        let mut x = TrackChange::new(1);
+        *x = 2;
+        assert!(x.changed());
+        // compared with 2, value at last check
+        *x = 2;
+        assert!(!x.changed());
+    }
+
+    #[test]
+    fn reset() {
+        let mut x = TrackChange::new(1);
+        *x = 2;
+        x.reset();
+        assert!(!x.peek_changed());
+        x.set(3);
+        x.reset();
+        assert!(!x.changed());
+    }
+}
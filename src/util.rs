@@ -0,0 +1,150 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use indicatif::HumanBytes;
+
+use crate::avgspeed::get_speed;
+use crate::error::{PpcpError, Result};
+
+/// "4K", "1.5G", "10MB" or plain number of bytes. like rsync, K, M, G, T and KiB, MiB.. are powers of 1024,
+/// KB, MB.. of 1000
+pub fn parse_size(s: &str) -> Result<usize> {
+    let invalid = || PpcpError::InvalidArgument{name: "size", value: s.to_string()};
+    let upper = s.trim().to_ascii_uppercase();
+    let (num, suffix) = upper.split_at(upper.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or_else(|| upper.len()));
+    let (exp, unit) = match suffix.chars().next() {
+        None | Some('B') => (0, suffix),
+        Some(c) => ("KMGT".find(c).ok_or_else(invalid)? as u32 + 1, &suffix[1..]),
+    };
+    let base: usize = match unit {
+        "" | "B" if exp == 0 => 1,
+        "" | "IB" => 1024,
+        "B" => 1000,
+        _ => Err(invalid())?,
+    };
+    let mult = base.checked_pow(exp).ok_or_else(invalid)?;
+    if num.contains('.') {
+        // fraction of a byte makes no sense
+        let num = num.parse::<f64>().map_err(|_| invalid())?;
+        let bytes = (num * mult as f64).round();
+        if exp == 0 || bytes > std::usize::MAX as f64 {
+            Err(invalid())?
+        }
+        return Ok(bytes as usize);
+    }
+    let num = num.parse::<usize>().map_err(|_| invalid())?;
+    num.checked_mul(mult).ok_or_else(invalid)
+}
+
+/// --newer-than, --older-than: "2024-01-31", "2024-01-31 12:30" or "2024-01-31T12:30:45" in local time,
+/// or age like "45s", "30m", "12h", "7d", "2w" before now
+pub fn parse_time(s: &str) -> Result<SystemTime> {
+    let invalid = || PpcpError::InvalidArgument{name: "time", value: s.to_string()};
+    let s = s.trim();
+    if let Some(unit) = s.chars().last().filter(|c| c.is_ascii_alphabetic()) {
+        let secs = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => Err(invalid())?,
+        };
+        let n = s[..s.len() - 1].parse::<u64>().map_err(|_| invalid())?;
+        let age = n.checked_mul(secs).ok_or_else(invalid)?;
+        return SystemTime::now().checked_sub(Duration::from_secs(age)).ok_or_else(invalid);
+    }
+    let (date, time) = match s.find(|c| c == ' ' || c == 'T') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, ""),
+    };
+    let num = |part: Option<&str>| part.and_then(|p| p.parse::<i32>().ok()).ok_or_else(invalid);
+    let mut d = date.split('-');
+    let (year, month, day) = (num(d.next())?, num(d.next())?, num(d.next())?);
+    let (hour, min, sec) = if time.is_empty() {
+        (0, 0, 0)
+    }
+    else {
+        let mut t = time.split(':');
+        let (hour, min) = (num(t.next())?, num(t.next())?);
+        let sec = match t.next() {
+            Some(p) => num(Some(p))?,
+            None => 0,
+        };
+        if t.next().is_some() {
+            Err(invalid())?
+        }
+        (hour, min, sec)
+    };
+    if d.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day)
+        || !(0..24).contains(&hour) || !(0..60).contains(&min) || !(0..61).contains(&sec) {
+        Err(invalid())?
+    }
+    // mktime knows local timezone and DST, like find -newermt
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = year - 1900;
+    tm.tm_mon = month - 1;
+    tm.tm_mday = day;
+    tm.tm_hour = hour;
+    tm.tm_min = min;
+    tm.tm_sec = sec;
+    tm.tm_isdst = -1;
+    let secs = unsafe { libc::mktime(&mut tm) };
+    if secs == -1 {
+        Err(invalid())?
+    }
+    Ok(if secs >= 0 { UNIX_EPOCH + Duration::from_secs(secs as u64) } else { UNIX_EPOCH - Duration::from_secs(-secs as u64) })
+}
+
+/// absolute path to p. unlike canonicalize, the last component is not resolved if it is a symlink
+pub fn absolute(p: &Path) -> io::Result<PathBuf> {
+    match (p.parent(), p.file_name()) {
+        (Some(parent), Some(name)) if parent != Path::new("") => Ok(parent.canonicalize()?.join(name)),
+        (_, Some(name)) => Ok(std::env::current_dir()?.join(name)),
+        _ => p.canonicalize(),
+    }
+}
+
+/// "1.50 MiB/s" for bytes done in elapsed. no time measured: speed is unknown, not the largest number
+pub fn format_speed(bytes: u64, elapsed: Duration) -> String {
+    if elapsed == Duration::from_secs(0) {
+        return "?/s".to_string();
+    }
+    format!("{}/s", HumanBytes(get_speed(bytes, &elapsed)))
+}
+
+/// "path: NotFound (No such file or directory)"
+pub fn io_error_message(path: &Path, err: &std::io::Error) -> String {
+    format!("{}: {:?} ({})", path.display(), err.kind(), err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_speed_of_elapsed() {
+        let table = [
+            (0, Duration::from_nanos(0), "?/s"),
+            (std::u64::MAX, Duration::from_nanos(0), "?/s"),
+            (1, Duration::from_nanos(1), "953.67 MiB/s"),
+            (1024, Duration::from_micros(1), "976.56 MiB/s"),
+            (1 << 20, Duration::from_millis(1), "1000.00 MiB/s"),
+            (3 << 19, Duration::from_secs(1), "1.50 MiB/s"),
+            (3600, Duration::from_secs(3600), "1 B/s"),
+            (0, Duration::from_secs(1), "0 B/s"),
+        ];
+        for &(bytes, elapsed, expected) in table.iter() {
+            assert_eq!(format_speed(bytes, elapsed), expected, "{} bytes in {:?}", bytes, elapsed);
+        }
+    }
+
+    #[test]
+    fn format_speed_of_huge_byte_counts() {
+        assert_eq!(format_speed(std::u64::MAX, Duration::from_secs(1)), "16.00 EiB/s");
+        // more than u64::MAX bytes per second, capped
+        assert_eq!(format_speed(std::u64::MAX, Duration::from_nanos(1)), "16.00 EiB/s");
+        assert_eq!(format_speed(std::u64::MAX, Duration::from_secs(3600 * 24 * 365)), "544.77 GiB/s");
+    }
+}
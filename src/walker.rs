@@ -0,0 +1,488 @@
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::io::{self, BufRead};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::SystemTime;
+
+use clap::ArgMatches;
+
+use crate::copy::{EntryKind, SourceFile, SourceItem};
+use crate::events::{ErrorContext, OperationStatus, SpecialKind, StatsChange, WorkerEvent};
+use crate::error::{PpcpError, Result};
+use crate::filter::{Filter, Verdict};
+use crate::gitignore::GitIgnores;
+use crate::sources::SourceList;
+use crate::util::{absolute, parse_size, parse_time};
+
+/// --scan-first: found files and bytes are reported this often
+const SCAN_PROGRESS_FILES: u64 = 100;
+
+/// which symlinks are walked into or copied as files they point to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Symlinks {
+    /// -P, --no-follow-symlinks: all links are copied as links, sources given in command line too
+    Never,
+    /// --follow-symlinks-once, default: sources given in command line are followed, links found inside are copied
+    Roots,
+    /// -L, --follow-symlinks
+    Always,
+}
+
+/// finds source files in a thread of its own and sends them to operation
+pub struct SourceWalker {
+    /// --exclude, --include rules
+    filter: Filter,
+    /// --min-depth, --max-depth: like find, 1 is right in source dir. each source is counted from itself
+    min_depth: usize,
+    max_depth: Option<usize>,
+    /// --min-size, --max-size: regular files out of range are excluded
+    min_size: u64,
+    max_size: Option<u64>,
+    /// --newer-than, --older-than: files and links modified out of range are excluded.
+    /// mtime in the future is newer than anything
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+    /// walk all sources before sending the first file
+    scan_first: bool,
+    /// file with source paths, one per line, relative to source root
+    files_from: Option<String>,
+    /// -0: paths in files_from end with NUL
+    from0: bool,
+    /// -L: walk into linked dirs, send link targets as regular files
+    symlinks: Symlinks,
+    /// -x: stay on filesystem of every source
+    one_file_system: bool,
+    /// --gitignore: files ignored by git are excluded too. read as dirs are walked
+    gitignore: Option<RefCell<GitIgnores>>,
+    /// --no-hidden: dot files and dirs are not walked, sources given in command line are
+    no_hidden: bool,
+    /// --strict: source in command line without files to copy is an error
+    strict: bool,
+    /// --special-files: send fifos, and device nodes if we are root, to be recreated
+    special_files: bool,
+    /// mknod of device nodes needs root
+    root: bool,
+}
+
+/// None for regular files, dirs and symlinks
+fn special_kind(file_type: std::fs::FileType) -> Option<SpecialKind> {
+    if file_type.is_fifo() {
+        Some(SpecialKind::Fifo)
+    }
+    else if file_type.is_socket() {
+        Some(SpecialKind::Socket)
+    }
+    else if file_type.is_char_device() || file_type.is_block_device() {
+        Some(SpecialKind::Device)
+    }
+    else {
+        None
+    }
+}
+
+/// -x: dir inside source is on another filesystem than source root, it's a mount point.
+/// dev is device id of the entry
+fn is_mount_point(root_dev: u64, depth: usize, is_dir: bool, dev: u64) -> bool {
+    depth > 0 && is_dir && dev != root_dev
+}
+
+/// name starts with a dot, or has the hidden attribute on windows
+fn is_hidden(entry: &walkdir::DirEntry) -> bool {
+    if entry.file_name().as_bytes().starts_with(b".") {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        return entry.metadata().map_or(false, |m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0);
+    }
+    #[cfg(not(windows))]
+    false
+}
+
+impl SourceWalker {
+    /// filters and limits from cp, mv or sync arguments
+    pub fn new(matches: &ArgMatches) -> Result<Self> {
+        let min_depth = matches.value_of("min-depth").unwrap_or("0").parse()?;
+        let max_depth = match matches.value_of("max-depth") {
+            Some(n) => Some(n.parse()?),
+            None => None,
+        };
+        if max_depth.map_or(false, |max| min_depth > max) {
+            Err(PpcpError::InvalidArgument { name: "min-depth", value: format!("{}, more than --max-depth", min_depth) })?
+        }
+        let min_size = parse_size(matches.value_of("min-size").unwrap_or("0"))? as u64;
+        let max_size = match matches.value_of("max-size") {
+            Some(size) => Some(parse_size(size)? as u64),
+            None => None,
+        };
+        if max_size.map_or(false, |max| min_size > max) {
+            Err(PpcpError::InvalidArgument { name: "min-size", value: format!("{}, more than --max-size", min_size) })?
+        }
+        let newer_than = match matches.value_of("newer-than") {
+            Some(t) => Some(parse_time(t)?),
+            None => None,
+        };
+        let older_than = match matches.value_of("older-than") {
+            Some(t) => Some(parse_time(t)?),
+            None => None,
+        };
+        Ok(SourceWalker {
+            filter: Filter::new(matches)?,
+            min_depth,
+            max_depth,
+            min_size,
+            max_size,
+            newer_than,
+            older_than,
+            scan_first: matches.is_present("scan-first"),
+            files_from: matches.value_of("files-from").map(String::from),
+            from0: matches.is_present("from0"),
+            symlinks: if matches.is_present("dereference") {
+                Symlinks::Always
+            }
+            // like cp -a: links in command line too are copied as links
+            else if matches.is_present("no-dereference") || matches.is_present("archive") {
+                Symlinks::Never
+            }
+            else {
+                Symlinks::Roots
+            },
+            one_file_system: matches.is_present("one-file-system"),
+            gitignore: if matches.is_present("gitignore") { Some(RefCell::new(GitIgnores::new())) } else { None },
+            no_hidden: matches.is_present("no-hidden"),
+            strict: matches.is_present("strict"),
+            special_files: matches.is_present("special-files"),
+            root: unsafe { libc::geteuid() } == 0,
+        })
+    }
+
+    /// entries matching no --exclude or --include are copied unless git ignores them
+    fn verdict(&self, root: &Path, entry: &walkdir::DirEntry) -> Verdict {
+        if entry.depth() == 0 {
+            // source given in command line
+            return Verdict::Copy;
+        }
+        let rel = entry.path().strip_prefix(root).unwrap_or_else(|_| entry.path());
+        let is_dir = entry.file_type().is_dir();
+        match self.filter.check(rel, is_dir) {
+            // --include brings back ignored file
+            Verdict::Unmatched => match self.gitignore {
+                Some(ref ignores) if ignores.borrow_mut().is_ignored(entry.path(), is_dir) => Verdict::Exclude,
+                _ => Verdict::Copy,
+            },
+            verdict => verdict,
+        }
+    }
+
+    /// excluded entries are counted through worker_tx
+    pub fn run(self, tx: Sender<SourceItem>, worker_tx: Sender<WorkerEvent>, sources: SourceList) {
+        thread::spawn(move || {
+            // --scan-first: everything is found before the first file is sent, so totals are final from start
+            let mut scanned: Vec<SourceItem> = Vec::new();
+            let (mut files, mut bytes) = (0, 0);
+            let mut send = |item: SourceItem| {
+                if !self.scan_first {
+                    return tx.send(item).is_ok();
+                }
+                match item {
+                    // dirs are not counted, they have no bytes
+                    Ok(ref file) if file.4 != EntryKind::Dir => {
+                        files += 1;
+                        bytes += file.2;
+                        if files % SCAN_PROGRESS_FILES == 0 {
+                            worker_tx.send(WorkerEvent::Scanning(files, bytes)).unwrap_or(());
+                        }
+                    }
+                    _ => {}
+                }
+                scanned.push(item);
+                true
+            };
+            let walked = match self.files_from {
+                Some(ref list) => self.walk_list(list, &sources, &worker_tx, &mut send),
+                None => sources.into_iter().all(|src| self.walk_source(src, &worker_tx, &mut send)),
+            };
+            if !walked {
+                // operation aborted
+                return;
+            }
+            if self.scan_first {
+                worker_tx.send(WorkerEvent::Scanning(files, bytes)).unwrap_or(());
+            }
+            for item in scanned {
+                if tx.send(item).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// absolute path of source. link is resolved unless it's copied as link
+    fn resolve(&self, src: &Path) -> io::Result<PathBuf> {
+        // "link/" is the dir it points to, like in cp
+        if self.symlinks == Symlinks::Never && std::fs::symlink_metadata(src)?.file_type().is_symlink() {
+            return absolute(src);
+        }
+        src.canonicalize()
+    }
+
+    /// walk source given in command line. false if operation is aborted
+    fn walk_source(&self, src: PathBuf, worker_tx: &Sender<WorkerEvent>, send: &mut dyn FnMut(SourceItem) -> bool) -> bool {
+        let resolved = match self.resolve(&src) {
+            Ok(resolved) => resolved,
+            // source is gone since it was checked, or not accessible. report and go on with the rest
+            Err(err) => return send(Err(ErrorContext { src, dest: None, err })),
+        };
+        let mut files = 0;
+        let walked = self.walk(&resolved, &resolved, worker_tx, &mut |item: SourceItem| {
+            if let Ok(ref file) = item {
+                if file.4 != EntryKind::Dir {
+                    files += 1;
+                }
+            }
+            send(item)
+        });
+        if walked && self.strict && files == 0 {
+            // empty dir, or everything in it excluded
+            let err = io::Error::new(io::ErrorKind::NotFound, "no files to copy, --strict");
+            return send(Err(ErrorContext { src, dest: None, err }));
+        }
+        walked
+    }
+
+    /// --files-from: walk every path listed in file, "-" is stdin. sources is the source root.
+    /// paths are walked as they are read, totals grow while the list is still streaming
+    fn walk_list(&self, list: &str, sources: &[PathBuf], worker_tx: &Sender<WorkerEvent>,
+                 send: &mut dyn FnMut(SourceItem) -> bool) -> bool {
+        let root = match sources.first().map(|root| root.canonicalize()) {
+            Some(Ok(root)) => root,
+            Some(Err(err)) => return send(Err(ErrorContext { src: sources[0].clone(), dest: None, err })),
+            None => return true,
+        };
+        let stdin = io::stdin();
+        let reader: Box<dyn BufRead> = if list == "-" {
+            Box::new(stdin.lock())
+        }
+        else {
+            match std::fs::File::open(list) {
+                Ok(f) => Box::new(io::BufReader::new(f)),
+                Err(err) => return send(Err(ErrorContext { src: PathBuf::from(list), dest: None, err })),
+            }
+        };
+        // names are bytes: no lossy conversion of names that are not utf-8
+        for line in reader.split(if self.from0 { b'\0' } else { b'\n' }) {
+            let mut line = match line {
+                Ok(line) => line,
+                Err(err) => return send(Err(ErrorContext { src: PathBuf::from(list), dest: None, err })),
+            };
+            if !self.from0 {
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                // any name is valid with -0, comments too
+                if line.iter().all(u8::is_ascii_whitespace) || line.first() == Some(&b'#') {
+                    continue;
+                }
+            }
+            else if line.is_empty() {
+                continue;
+            }
+            let line = OsStr::from_bytes(&line);
+            let path = match self.resolve(&root.join(line)) {
+                Ok(path) => path,
+                // stale list entry: reported at the end, the rest is copied without asking
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                    worker_tx.send(WorkerEvent::Stat(StatsChange::SourceMissing(root.join(line)))).unwrap_or(());
+                    continue;
+                }
+                Err(err) => {
+                    if !send(Err(ErrorContext { src: root.join(line), dest: None, err })) {
+                        return false;
+                    }
+                    continue;
+                }
+            };
+            // path outside of root goes right into destination
+            let path_root = if path.starts_with(&root) { root.clone() } else { path.clone() };
+            if !self.walk(&path_root, &path, worker_tx, send) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// send files under path, relative to source root. false if operation is aborted
+    fn walk(&self, root: &Path, path: &Path, worker_tx: &Sender<WorkerEvent>, send: &mut dyn FnMut(SourceItem) -> bool) -> bool {
+        let mut walk = walkdir::WalkDir::new(path)
+            .follow_links(self.symlinks == Symlinks::Always)
+            .follow_root_links(self.symlinks != Symlinks::Never);
+        let (mut file_depth, mut dir_depth) = (0, 0);
+        let is_dir = match self.symlinks {
+            Symlinks::Never => std::fs::symlink_metadata(path).map_or(false, |m| m.is_dir()),
+            _ => path.is_dir(),
+        };
+        if is_dir {
+            // source dir itself is depth 0 of walkdir, files right in it are 1.
+            // dirs one level above min depth are sent to be created, first files are in them.
+            // not walkdir min_depth: it doesn't pass shallower dirs to filter_entry, excluded ones would be walked
+            file_depth = self.min_depth;
+            dir_depth = self.min_depth.saturating_sub(1);
+            if let Some(max) = self.max_depth {
+                walk = walk.max_depth(max);
+            }
+        }
+        // bind mounts of the same device are walked, the id is the same
+        let dev = if self.one_file_system { std::fs::metadata(path).map(|m| m.dev()).ok() } else { None };
+        // -x: mount points are created empty, like cp -x and rsync -x. sent after the walk, they are never opened
+        let mount_points: RefCell<Vec<SourceFile>> = RefCell::new(Vec::new());
+        for entry in walk.into_iter().filter_entry(|e| {
+            if self.no_hidden && e.depth() > 0 && is_hidden(e) {
+                worker_tx.send(WorkerEvent::Stat(StatsChange::HiddenSkipped)).unwrap_or(());
+                return false;
+            }
+            if self.verdict(root, e) == Verdict::Exclude {
+                worker_tx.send(WorkerEvent::Stat(StatsChange::FileExcluded)).unwrap_or(());
+                return false;
+            }
+            if let Some(dev) = dev {
+                match e.metadata() {
+                    Ok(ref m) if is_mount_point(dev, e.depth(), e.file_type().is_dir(), m.dev()) => {
+                        worker_tx.send(WorkerEvent::Stat(StatsChange::MountSkipped(e.path().to_owned()))).unwrap_or(());
+                        // excluded dir is created only for included files, there are none here
+                        if self.verdict(root, e) != Verdict::Descend {
+                            mount_points.borrow_mut().push((root.to_owned(), e.path().to_owned(), 0, m.permissions(), EntryKind::Dir));
+                        }
+                        return false;
+                    }
+                    _ => {}
+                }
+            }
+            true
+        }) {
+            match entry {
+                Ok(entry) => {
+                    // file_type is of link target with -L, of link itself otherwise
+                    if entry.file_type().is_dir() {
+                        // above min depth or excluded, walked for files only. they create it if there are any
+                        if entry.depth() < dir_depth || self.verdict(root, &entry) == Verdict::Descend {
+                            continue;
+                        }
+                        let perm = match entry.metadata() {
+                            Ok(m) => m.permissions(),
+                            Err(err) => {
+                                if !send(Err(ErrorContext { src: entry.into_path(), dest: None, err: err.into() })) {
+                                    return false;
+                                }
+                                continue;
+                            }
+                        };
+                        if !send(Ok((root.to_owned(), entry.into_path(), 0, perm, EntryKind::Dir))) {
+                            return false;
+                        }
+                    }
+                    else if entry.depth() >= file_depth && (entry.file_type().is_file() || entry.file_type().is_symlink()) {
+                        // metadata of link itself, works for broken links too
+                        let m = match entry.metadata() {
+                            Ok(m) => m,
+                            Err(err) => {
+                                // file is gone since readdir, or no permission
+                                if !send(Err(ErrorContext { src: entry.into_path(), dest: None, err: err.into() })) {
+                                    return false;
+                                }
+                                continue;
+                            }
+                        };
+                        let kind = if m.file_type().is_symlink() { EntryKind::Symlink } else { EntryKind::File };
+                        // link is one file of zero bytes, its target is not copied
+                        let size = if kind == EntryKind::Symlink { 0 } else { m.len() };
+                        if kind == EntryKind::File && (size < self.min_size || self.max_size.map_or(false, |max| size > max)) {
+                            worker_tx.send(WorkerEvent::Stat(StatsChange::FileFiltered)).unwrap_or(());
+                            continue;
+                        }
+                        if self.newer_than.is_some() || self.older_than.is_some() {
+                            // no mtime: can't tell, copy it
+                            let mtime = m.modified().ok();
+                            if mtime.map_or(false, |t| self.newer_than.map_or(false, |newer| t < newer)
+                                                     || self.older_than.map_or(false, |older| t >= older)) {
+                                worker_tx.send(WorkerEvent::Stat(StatsChange::FileFiltered)).unwrap_or(());
+                                continue;
+                            }
+                        }
+                        let perm = m.permissions();
+                        if !send(Ok((root.to_owned(), entry.into_path(), size, perm, kind))) {
+                            return false;
+                        }
+                    }
+                    else if let (true, Some(special)) = (entry.depth() >= file_depth, special_kind(entry.file_type())) {
+                        // never opened: reading a fifo would wait for a writer forever
+                        let copied = match special {
+                            SpecialKind::Fifo => self.special_files,
+                            SpecialKind::Device => self.special_files && self.root,
+                            SpecialKind::Socket => false,
+                        };
+                        if !copied {
+                            if special == SpecialKind::Socket {
+                                let msg = format!("{}: socket, can't be copied", entry.path().display());
+                                worker_tx.send(WorkerEvent::Status(OperationStatus::Warning(msg))).unwrap_or(());
+                            }
+                            worker_tx.send(WorkerEvent::Stat(StatsChange::SpecialSkipped(special, entry.into_path()))).unwrap_or(());
+                            continue;
+                        }
+                        let perm = match entry.metadata() {
+                            Ok(m) => m.permissions(),
+                            Err(err) => {
+                                if !send(Err(ErrorContext { src: entry.into_path(), dest: None, err: err.into() })) {
+                                    return false;
+                                }
+                                continue;
+                            }
+                        };
+                        if !send(Ok((root.to_owned(), entry.into_path(), 0, perm, EntryKind::Special))) {
+                            return false;
+                        }
+                    }
+                }
+                Err(err) => {
+                    // walkdir skips dirs it can't read and goes on.
+                    // with -L also links to own ancestor dir, with loop error
+                    let path = err.path().map(Path::to_owned).unwrap_or_default();
+                    if !send(Err(ErrorContext { src: path, dest: None, err: err.into() })) {
+                        return false;
+                    }
+                }
+            }
+        }
+        mount_points.into_inner().into_iter().all(|dir| send(Ok(dir)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_point_is_dir_on_other_device() {
+        // /home on 1, /home/user/.cache is tmpfs on 2
+        let home = 1;
+        assert!(!is_mount_point(home, 1, true, 1));
+        assert!(is_mount_point(home, 2, true, 2));
+    }
+
+    #[test]
+    fn source_root_is_never_mount_point() {
+        // depth 0 is the source given in command line, it is walked whatever device it reports
+        assert!(!is_mount_point(2, 0, true, 1));
+    }
+
+    #[test]
+    fn file_on_other_device_is_copied() {
+        // bind mounted file is data like any other
+        assert!(!is_mount_point(1, 1, false, 2));
+    }
+}
@@ -0,0 +1,420 @@
+//! runs the ppcp binary on temp dirs
+extern crate filetime;
+extern crate serde_json;
+extern crate tempfile;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use filetime::FileTime;
+use tempfile::TempDir;
+
+/// temp dir with empty src and dest dirs. it is also home of ppcp, so user config isn't read
+struct Fixture {
+    tmp: TempDir,
+    src: PathBuf,
+    dest: PathBuf,
+}
+
+impl Fixture {
+    fn new() -> Fixture {
+        let tmp = tempfile::tempdir().unwrap();
+        let (src, dest) = (tmp.path().join("src"), tmp.path().join("dest"));
+        fs::create_dir(&src).unwrap();
+        fs::create_dir(&dest).unwrap();
+        Fixture { tmp, src, dest }
+    }
+
+    fn join(&self, rel: &str) -> PathBuf {
+        self.tmp.path().join(rel)
+    }
+
+    /// file under temp dir, parent dirs created
+    fn write(&self, rel: &str, data: &[u8]) {
+        let p = self.join(rel);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        fs::write(p, data).unwrap();
+    }
+
+    fn read(&self, rel: &str) -> Vec<u8> {
+        fs::read(self.join(rel)).unwrap_or_else(|e| panic!("{}: {}", rel, e))
+    }
+
+    /// ppcp with args, without prompts
+    fn run(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_ppcp"))
+            .args(args)
+            .env("XDG_CONFIG_HOME", self.tmp.path())
+            .env("HOME", self.tmp.path())
+            .output()
+            .expect("run ppcp")
+    }
+
+    /// `ppcp cp --progress none args`, has to succeed
+    fn cp(&self, args: &[&str]) {
+        let out = self.run(&[&["cp", "--progress", "none"], args].concat());
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+
+    /// `ppcp cp --progress none args`, has to fail without a panic. returns stderr
+    fn cp_fails(&self, args: &[&str]) -> String {
+        let out = self.run(&[&["cp", "--progress", "none"], args].concat());
+        let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+        assert!(!out.status.success(), "{}", stderr);
+        // 101 is exit status of panic
+        assert_ne!(out.status.code(), Some(101), "{}", stderr);
+        stderr
+    }
+
+    /// `ppcp cp --progress json args`, has to succeed. returns summary, the last line
+    fn cp_summary(&self, args: &[&str]) -> serde_json::Value {
+        let out = self.run(&[&["cp", "--progress", "json"], args].concat());
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        let summary: serde_json::Value = serde_json::from_str(stdout.lines().last().expect("summary line")).unwrap();
+        assert_eq!(summary["event"].as_str(), Some("summary"), "{}", summary);
+        summary
+    }
+
+    /// config.toml where ppcp finds it
+    fn write_config(&self, text: &str) {
+        self.write("ppcp/config.toml", text.as_bytes());
+    }
+
+    /// src/f and dest/f with other bytes, dest/other only in dest
+    fn with_src_dest(self) -> Fixture {
+        self.write("src/f", b"source");
+        self.write("dest/f", b"destination");
+        self.write("dest/other", b"other");
+        self
+    }
+
+    /// src with a file, a dir and a link to each, and srclink to src itself
+    fn with_links(self) -> Fixture {
+        use std::os::unix::fs::symlink;
+        self.write("src/file", b"file");
+        self.write("src/dir/x", b"x");
+        symlink("file", self.src.join("link_file")).unwrap();
+        symlink("dir", self.src.join("link_dir")).unwrap();
+        symlink("src", self.join("srclink")).unwrap();
+        self
+    }
+}
+
+fn path(p: &Path) -> &str {
+    p.to_str().expect("utf-8 temp path")
+}
+
+/// bytes that differ from file to file and from block to block
+fn content(seed: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i / 7 + seed * 31) as u8).collect()
+}
+
+/// dirs three levels deep, files of several sizes. empty, smaller and larger than a block
+fn make_tree(root: &Path) {
+    let sizes = [0, 1, 4095, 4096, 100_000, 1 << 20, (3 << 20) + 17];
+    for (i, dir) in ["", "a", "a/b", "a/b/c", "d"].iter().enumerate() {
+        fs::create_dir_all(root.join(dir)).unwrap();
+        for (j, size) in sizes.iter().enumerate() {
+            fs::write(root.join(dir).join(format!("f{}", j)), content(i * 10 + j, *size)).unwrap();
+        }
+    }
+}
+
+/// same dirs and files with same bytes in both
+fn assert_same_tree(expected: &Path, actual: &Path) {
+    let mut names: Vec<_> = fs::read_dir(expected).unwrap().map(|e| e.unwrap().file_name()).collect();
+    let mut actual_names: Vec<_> = fs::read_dir(actual).unwrap().map(|e| e.unwrap().file_name()).collect();
+    names.sort();
+    actual_names.sort();
+    assert_eq!(names, actual_names, "{}", actual.display());
+    for name in names {
+        let (e, a) = (expected.join(&name), actual.join(&name));
+        if e.is_dir() {
+            assert_same_tree(&e, &a);
+        }
+        else {
+            assert!(fs::read(&e).unwrap() == fs::read(&a).unwrap(), "{} differs", a.display());
+        }
+    }
+}
+
+/// mtime seconds ago, far more than tolerance of --update
+fn set_age(p: &Path, secs: i64) {
+    let t = FileTime::from_unix_time(FileTime::now().unix_seconds() - secs, 0);
+    filetime::set_symlink_file_times(p, t, t).unwrap();
+}
+
+#[test]
+fn missing_source_is_error_not_panic() {
+    let f = Fixture::new();
+    let dest = f.join("new");
+    let stderr = f.cp_fails(&["/does/not/exist", path(&dest)]);
+    assert!(stderr.contains("/does/not/exist"), "{}", stderr);
+    assert!(!dest.exists());
+}
+
+#[test]
+fn inaccessible_source_is_error_not_panic() {
+    let f = Fixture::new();
+    f.write("src/a", b"a");
+    let dest = f.join("new");
+    // path through a regular file: ENOTDIR, other error than not found. chmod tests would pass as root
+    let stderr = f.cp_fails(&["--on-error", "skip", path(&f.src.join("a/b")), path(&dest)]);
+    assert!(stderr.contains("src/a/b"), "{}", stderr);
+    assert!(!dest.exists());
+}
+
+#[test]
+fn strict_source_with_all_files_excluded_is_error() {
+    let f = Fixture::new();
+    f.write("src/a.tmp", b"a");
+    f.write("src/d/b.tmp", b"b");
+    let args = ["--on-error", "skip", "--exclude", "*.tmp", path(&f.src), path(&f.dest)];
+    let stderr = f.cp_fails(&[&["--strict"], &args[..]].concat());
+    assert!(stderr.contains("no files to copy"), "{}", stderr);
+    f.cp(&args);
+    assert!(!f.dest.join("src/a.tmp").exists());
+}
+
+#[test]
+fn parallel_copy_is_identical() {
+    let f = Fixture::new();
+    make_tree(&f.src);
+    f.cp(&["-j", "4", path(&f.src), path(&f.dest)]);
+    assert_same_tree(&f.src, &f.dest.join("src"));
+}
+
+#[test]
+fn json_summary_counts_copy() {
+    let f = Fixture::new();
+    make_tree(&f.src);
+    let summary = f.cp_summary(&[path(&f.src), path(&f.dest)]);
+    // make_tree: 5 dirs with 7 files each, 4M+4096+4095+100000+18 bytes in each dir
+    assert_eq!(summary["files_copied"].as_u64(), Some(35), "{}", summary);
+    assert_eq!(summary["bytes_total"].as_u64(), Some(5 * ((4 << 20) + 4096 + 4095 + 100_000 + 18)), "{}", summary);
+}
+
+#[test]
+fn existing_dest_dir_gets_source_dir_inside() {
+    let f = Fixture::new().with_src_dest();
+    f.cp(&[path(&f.src), path(&f.dest)]);
+    assert_eq!(f.read("dest/src/f"), b"source");
+    assert_eq!(f.read("dest/f"), b"destination");
+}
+
+#[test]
+fn no_target_dir_copies_into_dest_itself() {
+    let f = Fixture::new().with_src_dest();
+    f.cp(&["-T", path(&f.src), path(&f.dest)]);
+    // overwritten, not nested. files only in dest stay
+    assert_eq!(f.read("dest/f"), b"source");
+    assert_eq!(f.read("dest/other"), b"other");
+    assert!(!f.dest.join("src").exists());
+}
+
+#[test]
+fn no_target_dir_with_two_sources_is_error() {
+    let f = Fixture::new().with_src_dest();
+    let stderr = f.cp_fails(&["-T", path(&f.src), path(&f.src.join("f")), path(&f.dest)]);
+    assert!(stderr.contains("no-target-directory"), "{}", stderr);
+    assert_eq!(f.read("dest/f"), b"destination");
+}
+
+#[test]
+fn no_target_dir_file_over_dir_is_error() {
+    let f = Fixture::new().with_src_dest();
+    f.cp_fails(&["-T", path(&f.src.join("f")), path(&f.dest)]);
+    assert!(f.dest.is_dir());
+    assert_eq!(f.read("dest/f"), b"destination");
+}
+
+#[test]
+fn target_dir_comes_first() {
+    let f = Fixture::new().with_src_dest();
+    f.write("src/g", b"g");
+    f.cp(&["-t", path(&f.dest), path(&f.src.join("f")), path(&f.src.join("g"))]);
+    assert_eq!(f.read("dest/f"), b"source");
+    assert_eq!(f.read("dest/g"), b"g");
+}
+
+#[test]
+fn max_depth_1_copies_top_level_files_only() {
+    let f = Fixture::new();
+    make_tree(&f.src);
+    f.cp(&["--max-depth", "1", path(&f.src), path(&f.dest)]);
+    for entry in fs::read_dir(f.dest.join("src")).unwrap() {
+        let entry = entry.unwrap();
+        // subdirs may be created, nothing gets into them
+        if entry.file_type().unwrap().is_dir() {
+            assert_eq!(fs::read_dir(entry.path()).unwrap().count(), 0, "{}", entry.path().display());
+        }
+        else {
+            assert!(fs::read(f.src.join(entry.file_name())).unwrap() == fs::read(entry.path()).unwrap());
+        }
+    }
+    assert!(f.dest.join("src/f6").is_file());
+    assert!(!f.dest.join("src/a/f0").exists());
+    assert!(!f.dest.join("src/a/b/c").exists());
+}
+
+#[test]
+fn follow_symlinks_once_by_default() {
+    let f = Fixture::new().with_links();
+    f.cp(&[path(&f.join("srclink")), path(&f.dest)]);
+    // command line link is followed, links inside are copied as links
+    assert!(fs::symlink_metadata(f.dest.join("srclink")).unwrap().is_dir());
+    assert_eq!(fs::read_link(f.dest.join("srclink/link_file")).unwrap(), Path::new("file"));
+    assert_eq!(fs::read_link(f.dest.join("srclink/link_dir")).unwrap(), Path::new("dir"));
+    assert_eq!(f.read("dest/srclink/dir/x"), b"x");
+}
+
+#[test]
+fn no_follow_symlinks_copies_command_line_link() {
+    let f = Fixture::new().with_links();
+    f.cp(&["--no-follow-symlinks", path(&f.join("srclink")), path(&f.dest)]);
+    assert_eq!(fs::read_link(f.dest.join("srclink")).unwrap(), Path::new("src"));
+}
+
+#[test]
+fn archive_copies_command_line_link() {
+    let f = Fixture::new().with_links();
+    f.cp(&["-a", path(&f.join("srclink")), path(&f.dest)]);
+    assert_eq!(fs::read_link(f.dest.join("srclink")).unwrap(), Path::new("src"));
+    f.cp(&["-a", path(&f.src.join("link_file")), path(&f.dest)]);
+    assert_eq!(fs::read_link(f.dest.join("link_file")).unwrap(), Path::new("file"));
+}
+
+#[test]
+fn follow_symlinks_copies_targets() {
+    let f = Fixture::new().with_links();
+    f.cp(&["--follow-symlinks", path(&f.src), path(&f.dest)]);
+    assert!(fs::symlink_metadata(f.dest.join("src/link_file")).unwrap().is_file());
+    assert_eq!(f.read("dest/src/link_file"), b"file");
+    assert!(fs::symlink_metadata(f.dest.join("src/link_dir")).unwrap().is_dir());
+    assert_eq!(f.read("dest/src/link_dir/x"), b"x");
+}
+
+#[test]
+fn symlinks_are_counted_apart() {
+    let f = Fixture::new().with_links();
+    let summary = f.cp_summary(&[path(&f.src), path(&f.dest)]);
+    assert_eq!(summary["symlinks_copied"].as_u64(), Some(2), "{}", summary);
+}
+
+#[test]
+fn config_values_are_defaults_command_line_wins() {
+    let f = Fixture::new();
+    // not a multiple of 4K: copy fails unless command line replaces it
+    f.write_config("block-size = \"5K\"\nexclude = [\"*.tmp\"]\n");
+    f.write("src/a.tmp", b"a");
+    f.write("src/b", b"b");
+    let stderr = f.cp_fails(&[path(&f.src), path(&f.dest)]);
+    assert!(stderr.contains("block-size"), "{}", stderr);
+    // alias of --block-size
+    f.cp(&["--buffer-size", "8K", path(&f.src), path(&f.dest)]);
+    assert_eq!(f.read("dest/src/b"), b"b");
+    assert!(!f.dest.join("src/a.tmp").exists());
+}
+
+#[test]
+fn config_dump_fills_in_built_in_defaults() {
+    let f = Fixture::new();
+    f.write_config("jobs = 3\n");
+    let out = f.run(&["config", "--dump"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("jobs = 3"), "{}", stdout);
+    assert!(stdout.contains("block-size = \"128K\""), "{}", stdout);
+    assert!(stdout.contains("on-error = \"ask\""), "{}", stdout);
+}
+
+#[test]
+fn broken_config_is_error() {
+    let f = Fixture::new();
+    f.write_config("jobs = 0\n");
+    let dest = f.join("new");
+    let stderr = f.cp_fails(&[path(&f.src), path(&dest)]);
+    assert!(stderr.contains("config.toml"), "{}", stderr);
+    assert!(!dest.exists());
+}
+
+#[test]
+fn rename_conflicts_copies_read_only_source() {
+    use std::os::unix::fs::PermissionsExt;
+    let f = Fixture::new();
+    f.write("src/f", b"source");
+    fs::set_permissions(f.src.join("f"), fs::Permissions::from_mode(0o444)).unwrap();
+    f.write("dest/src/f", b"destination");
+    f.cp(&["--rename-conflicts", path(&f.src), path(&f.dest)]);
+    assert_eq!(f.read("dest/src/f (1)"), b"source");
+    // mode of source, not of the placeholder. umask may only take bits away
+    let mode = fs::metadata(f.dest.join("src/f (1)")).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode & !0o444, 0, "{:o}", mode);
+    assert_eq!(f.read("dest/src/f"), b"destination");
+}
+
+#[test]
+fn preserve_acl_without_mode_is_error() {
+    let f = Fixture::new();
+    f.write("src/f", b"f");
+    let stderr = f.cp_fails(&["--preserve=acl", "--no-preserve=mode", path(&f.src), path(&f.dest)]);
+    assert!(stderr.contains("acl"), "{}", stderr);
+    assert!(!f.dest.join("src").exists());
+}
+
+#[test]
+fn update_skips_newer_and_replaces_older() {
+    let f = Fixture::new();
+    for name in &["newer", "older"] {
+        f.write(&format!("src/{}", name), b"source");
+        f.write(&format!("dest/src/{}", name), b"destination");
+        set_age(&f.src.join(name), 1000);
+    }
+    set_age(&f.dest.join("src/newer"), 10);
+    set_age(&f.dest.join("src/older"), 5000);
+    f.cp(&["--update", path(&f.src), path(&f.dest)]);
+    assert_eq!(f.read("dest/src/newer"), b"destination");
+    assert_eq!(f.read("dest/src/older"), b"source");
+}
+
+#[test]
+fn update_does_not_skip_dir_in_place_of_file() {
+    let f = Fixture::new();
+    f.write("src/f", b"source");
+    set_age(&f.src.join("f"), 1000);
+    // newer, but a dir: not up to date, copy reports it can't write there
+    fs::create_dir_all(f.dest.join("src/f")).unwrap();
+    let stderr = f.cp_fails(&["--on-error", "skip", "--update", path(&f.src), path(&f.dest)]);
+    assert!(stderr.contains("failed 1 files"), "{}", stderr);
+    assert!(f.dest.join("src/f").is_dir());
+}
+
+#[test]
+fn on_error_retry_retries_any_error() {
+    let f = Fixture::new();
+    f.write("src/f", b"source");
+    // EISDIR is no transient error, --retries alone wouldn't copy it again
+    fs::create_dir_all(f.dest.join("src/f")).unwrap();
+    let out = f.run(&["cp", "--progress", "json", "--on-error", "retry:1", path(&f.src), path(&f.dest)]);
+    assert!(!out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("\"auto_retry\""), "{}", stdout);
+    let out = f.run(&["cp", "--progress", "json", "--on-error", "skip", "--retries", "1", path(&f.src), path(&f.dest)]);
+    assert!(!out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(!stdout.contains("\"auto_retry\""), "{}", stdout);
+}
+
+#[test]
+fn update_compares_symlink_itself() {
+    let f = Fixture::new();
+    f.write("src/target", b"target");
+    std::os::unix::fs::symlink("target", f.src.join("link")).unwrap();
+    set_age(&f.src.join("link"), 1000);
+    // newer regular file in place of the link: types differ, link is copied
+    f.write("dest/src/link", b"file");
+    f.cp(&["--update", path(&f.src), path(&f.dest)]);
+    assert_eq!(fs::read_link(f.dest.join("src/link")).unwrap(), Path::new("target"));
+}